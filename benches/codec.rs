@@ -0,0 +1,267 @@
+use adnl::common::serialize;
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use rldp::{RaptorqDecoder, RaptorqEncoder, RldpAnswerSource, RldpSendSource};
+use std::sync::Arc;
+use ton_api::ton::fec::type_::RaptorQ as FecTypeRaptorQ;
+use ton_api::ton::rldp::message::Answer as RldpAnswer;
+use ton_api::{ton, IntoBoxed};
+
+const ANSWER_SIZES: [usize; 2] = [64 * 1024, 5 * 1024 * 1024];
+const DATA_SIZE: usize = 2 * 1024 * 1024;
+const PART_SIZE: usize = 2000000; // Data of single RLDP transfer part
+const DATA_SIZES: [usize; 3] = [16 * 1024, 256 * 1024, 2 * 1024 * 1024];
+const LOSS_PERCENT: u32 = 10;
+const SYMBOL_SIZES: [usize; 3] = [256, 768, 1280];
+
+fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+fn params(encoder: &RaptorqEncoder) -> FecTypeRaptorQ {
+    let params = encoder.params();
+    FecTypeRaptorQ {
+        data_size: params.data_size,
+        symbol_size: params.symbol_size,
+        symbols_count: params.symbols_count,
+    }
+}
+
+// Symbols as received over lossy link: some source symbols are lost, repair symbols follow
+fn symbols(encoder: &RaptorqEncoder, loss_percent: u32) -> Vec<(u32, Vec<u8>)> {
+    let mut encoder = encoder.clone();
+    let count = encoder.params().symbols_count as u32;
+    let mut ret = Vec::new();
+    let mut seqno = 0;
+    for i in 0..count {
+        let symbol = encoder.encode(&mut seqno).unwrap();
+        if i * loss_percent % 100 >= loss_percent {
+            ret.push((seqno, symbol))
+        }
+    }
+    seqno = count;
+    while ret.len() < count as usize * 11 / 10 + 2 {
+        let symbol = encoder.encode(&mut seqno).unwrap();
+        ret.push((seqno, symbol));
+        seqno += 1;
+    }
+    ret
+}
+
+fn sizes() -> impl Iterator<Item = (usize, usize)> {
+    DATA_SIZES.iter().flat_map(|data_size| {
+        SYMBOL_SIZES
+            .iter()
+            .map(move |symbol_size| (*data_size, *symbol_size))
+    })
+}
+
+fn bench_with_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encoder_with_data");
+    group.sample_size(10);
+    for (data_size, symbol_size) in sizes() {
+        let data = data(data_size);
+        group.throughput(Throughput::Bytes(data_size as u64));
+        group.bench_with_input(
+            BenchmarkId::new(format!("symbol_{}", symbol_size), data_size),
+            &data,
+            |b, data| b.iter(|| RaptorqEncoder::with_data_and_symbol_size(data, symbol_size)),
+        );
+    }
+    group.finish()
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_with_repair");
+    group.sample_size(10);
+    for (data_size, symbol_size) in sizes() {
+        let encoder = RaptorqEncoder::with_data_and_symbol_size(&data(data_size), symbol_size);
+        let count = encoder.params().symbols_count as u32;
+        group.throughput(Throughput::Bytes(data_size as u64));
+        group.bench_function(
+            BenchmarkId::new(format!("symbol_{}", symbol_size), data_size),
+            |b| {
+                b.iter_batched(
+                    || encoder.clone(),
+                    |mut encoder| {
+                        // Source symbols overwrite seqno, repair ones follow them
+                        for i in 0..count * (100 + LOSS_PERCENT) / 100 {
+                            let mut seqno = i;
+                            black_box(encoder.encode_symbol(&mut seqno).unwrap());
+                        }
+                    },
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish()
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_with_loss");
+    group.sample_size(10);
+    for (data_size, symbol_size) in sizes() {
+        let encoder = RaptorqEncoder::with_data_and_symbol_size(&data(data_size), symbol_size);
+        let symbols = symbols(&encoder, LOSS_PERCENT);
+        group.throughput(Throughput::Bytes(data_size as u64));
+        group.bench_function(
+            BenchmarkId::new(format!("symbol_{}", symbol_size), data_size),
+            |b| {
+                b.iter_batched(
+                    || RaptorqDecoder::with_params(params(&encoder)),
+                    |mut decoder| {
+                        for (seqno, symbol) in symbols.iter() {
+                            if let Some(data) = decoder.decode(*seqno, symbol) {
+                                return black_box(data);
+                            }
+                        }
+                        panic!("Cannot decode data")
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish()
+}
+
+fn bench_encode_copy(c: &mut Criterion) {
+    let encoder = RaptorqEncoder::with_data(&data(DATA_SIZE));
+    let count = encoder.params().symbols_count as usize;
+    let mut group = c.benchmark_group("encode_source_symbols");
+    group.throughput(Throughput::Bytes(DATA_SIZE as u64));
+    group.bench_function("copied", |b| {
+        b.iter_batched(
+            || encoder.clone(),
+            |mut encoder| {
+                for _ in 0..count {
+                    let mut seqno = 0;
+                    black_box(encoder.encode(&mut seqno).unwrap());
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("zero_copy", |b| {
+        b.iter_batched(
+            || encoder.clone(),
+            |mut encoder| {
+                for _ in 0..count {
+                    let mut seqno = 0;
+                    black_box(encoder.encode_symbol(&mut seqno).unwrap());
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish()
+}
+
+fn bench_decode_copy(c: &mut Criterion) {
+    let encoder = RaptorqEncoder::with_data(&data(DATA_SIZE));
+    let symbols = symbols(&encoder, 0);
+    let mut group = c.benchmark_group("decode_source_symbols");
+    group.throughput(Throughput::Bytes(DATA_SIZE as u64));
+    group.sample_size(20);
+    group.bench_function("copied", |b| {
+        b.iter_batched(
+            || RaptorqDecoder::with_params(params(&encoder)),
+            |mut decoder| {
+                for (seqno, symbol) in symbols.iter() {
+                    if let Some(data) = decoder.decode(*seqno, symbol) {
+                        return black_box(data);
+                    }
+                }
+                panic!("Cannot decode data")
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("zero_copy", |b| {
+        b.iter_batched(
+            || {
+                (
+                    RaptorqDecoder::with_params(params(&encoder)),
+                    symbols.clone(),
+                )
+            },
+            |(mut decoder, symbols)| {
+                for (seqno, symbol) in symbols {
+                    if let Some(data) = decoder.decode_owned(seqno, symbol) {
+                        return black_box(data);
+                    }
+                }
+                panic!("Cannot decode data")
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish()
+}
+
+// Answer framing and encoding part by part, as answer transfer does it
+fn bench_answer_encode(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let query_id = ton::int256([1; 32]);
+    let mut group = c.benchmark_group("answer_encode");
+    group.sample_size(10);
+    for answer_size in ANSWER_SIZES.iter() {
+        let answer = Arc::new(data(*answer_size));
+        group.throughput(Throughput::Bytes(*answer_size as u64));
+        // Whole answer is serialized first, then sliced
+        group.bench_with_input(
+            BenchmarkId::new("serialized", answer_size),
+            &answer,
+            |b, answer| {
+                b.iter(|| {
+                    let serialized = serialize(
+                        &RldpAnswer {
+                            query_id: ton::int256([1; 32]),
+                            data: ton::bytes(answer.to_vec()),
+                        }
+                        .into_boxed(),
+                    )
+                    .unwrap();
+                    for part in serialized.chunks(PART_SIZE) {
+                        black_box(RaptorqEncoder::with_data(part));
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("answer_source", answer_size),
+            &answer,
+            |b, answer| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        let mut source =
+                            RldpAnswerSource::with_source(&query_id, Box::new(answer.clone()))
+                                .unwrap();
+                        let size = source.size();
+                        for offset in (0..size).step_by(PART_SIZE) {
+                            let len = std::cmp::min(size - offset, PART_SIZE);
+                            let part = source.read(offset, len).await.unwrap();
+                            black_box(RaptorqEncoder::with_data(&part));
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish()
+}
+
+criterion_group!(
+    benches,
+    bench_with_data,
+    bench_encode,
+    bench_decode,
+    bench_encode_copy,
+    bench_decode_copy,
+    bench_answer_encode
+);
+criterion_main!(benches);
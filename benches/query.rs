@@ -0,0 +1,149 @@
+use adnl::common::{deserialize, AdnlPeers, KeyId, Subscriber};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::Rng;
+use rldp::{RldpNode, RldpNodeOptions, RldpSendSource, RldpSourceSubscriber, RldpTransport};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use ton_api::ton::rldp::message::Query as RldpQuery;
+use ton_api::ton::rldp::MessagePart as RldpMessagePartBoxed;
+use ton_types::Result;
+
+const ANSWER_SIZES: [usize; 2] = [64 * 1024, 1024 * 1024];
+const LOSS_PERCENTS: [u32; 3] = [0, 5, 20];
+
+// Delivers packets between nodes in the same process, dropping some of them.
+// Symbol bytes of answers sent by server are counted to estimate FEC overhead
+struct MemoryTransport {
+    answer_bytes: AtomicU64,
+    loss_percent: u32,
+    nodes: Mutex<HashMap<Arc<KeyId>, Weak<RldpNode>>>,
+    server: Arc<KeyId>,
+}
+
+impl MemoryTransport {
+    fn with_loss(loss_percent: u32, server: &Arc<KeyId>) -> Arc<Self> {
+        Arc::new(Self {
+            answer_bytes: AtomicU64::new(0),
+            loss_percent,
+            nodes: Mutex::new(HashMap::new()),
+            server: server.clone(),
+        })
+    }
+
+    fn add_node(&self, key: &Arc<KeyId>, node: &Arc<RldpNode>) {
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(key.clone(), Arc::downgrade(node));
+    }
+}
+
+#[async_trait::async_trait]
+impl RldpTransport for MemoryTransport {
+    async fn send_custom(&self, data: &[u8], peers: &AdnlPeers) -> Result<()> {
+        if peers.local() == &self.server {
+            if let Ok(RldpMessagePartBoxed::Rldp_MessagePart(msg)) =
+                deserialize(data)?.downcast::<RldpMessagePartBoxed>()
+            {
+                self.answer_bytes
+                    .fetch_add(msg.data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        if rand::thread_rng().gen_range(0, 100) < self.loss_percent {
+            return Ok(());
+        }
+        let node = self
+            .nodes
+            .lock()
+            .unwrap()
+            .get(peers.other())
+            .and_then(|node| node.upgrade());
+        if let Some(node) = node {
+            let peers = AdnlPeers::with_keys(peers.other().clone(), peers.local().clone());
+            let data = data.to_vec();
+            tokio::spawn(async move { node.try_consume_custom(&data, &peers).await });
+        }
+        Ok(())
+    }
+}
+
+// Answers every query with fixed size data
+struct FixedAnswer {
+    data: Arc<Vec<u8>>,
+}
+
+#[async_trait::async_trait]
+impl RldpSourceSubscriber for FixedAnswer {
+    async fn try_answer(
+        &self,
+        _query: &RldpQuery,
+        _peers: &AdnlPeers,
+    ) -> Result<Option<Box<dyn RldpSendSource>>> {
+        Ok(Some(Box::new(self.data.clone())))
+    }
+}
+
+fn bench_query(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("query");
+    group.sample_size(10);
+    for answer_size in ANSWER_SIZES.iter() {
+        for loss_percent in LOSS_PERCENTS.iter() {
+            let client_key = KeyId::from_data([1; 32]);
+            let server_key = KeyId::from_data([2; 32]);
+            let transport = MemoryTransport::with_loss(*loss_percent, &server_key);
+            let client = RldpNode::with_transport(
+                transport.clone(),
+                Vec::new(),
+                Vec::new(),
+                RldpNodeOptions::default(),
+            );
+            let server = RldpNode::with_transport(
+                transport.clone(),
+                Vec::new(),
+                vec![Arc::new(FixedAnswer {
+                    data: Arc::new(vec![0x5A; *answer_size]),
+                })],
+                RldpNodeOptions::default(),
+            );
+            transport.add_node(&client_key, &client);
+            transport.add_node(&server_key, &server);
+            let peers = AdnlPeers::with_keys(client_key, server_key);
+            let queries = AtomicU64::new(0);
+            group.throughput(Throughput::Bytes(*answer_size as u64));
+            group.bench_function(
+                BenchmarkId::new(format!("loss_{}%", loss_percent), answer_size),
+                |b| {
+                    b.iter(|| {
+                        rt.block_on(async {
+                            let (answer, _) = client
+                                .query(&[0xAB; 64], Some(*answer_size as i64 * 2), &peers, None)
+                                .await
+                                .unwrap();
+                            assert_eq!(answer.map(|answer| answer.len()), Some(*answer_size));
+                        });
+                        queries.fetch_add(1, Ordering::Relaxed);
+                    })
+                },
+            );
+            rt.block_on(async {
+                client.shutdown(None).await;
+                server.shutdown(None).await;
+            });
+            // Answer symbols only, query and confirmations are not counted
+            let payload = queries.load(Ordering::Relaxed) * *answer_size as u64;
+            let sent = transport.answer_bytes.load(Ordering::Relaxed);
+            println!(
+                "query/loss_{}%/{}: FEC overhead {:.1}%",
+                loss_percent,
+                answer_size,
+                (sent as f64 / payload as f64 - 1.0) * 100.0
+            );
+        }
+    }
+    group.finish()
+}
+
+criterion_group!(benches, bench_query);
+criterion_main!(benches);
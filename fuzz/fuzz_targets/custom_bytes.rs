@@ -0,0 +1,4 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|packets: Vec<Vec<u8>>| rldp_fuzz::run(packets));
@@ -0,0 +1,140 @@
+#![no_main]
+use adnl::common::serialize;
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rldp::RaptorqEncoder;
+use ton_api::ton::{
+    self,
+    fec::type_::RaptorQ as FecTypeRaptorQ,
+    rldp::messagepart::{
+        Complete as RldpComplete, Confirm as RldpConfirm, MessagePart as RldpMessagePart,
+    },
+};
+use ton_api::IntoBoxed;
+
+const MAX_ENCODED: usize = 16 * 1024;
+
+#[derive(Arbitrary, Debug)]
+enum Packet {
+    Complete {
+        transfer: u8,
+        part: i32,
+    },
+    Confirm {
+        transfer: u8,
+        part: i32,
+        seqno: i32,
+    },
+    // Symbols of data properly encoded, some of them lost
+    Encoded {
+        transfer: u8,
+        data: Vec<u8>,
+        lost: u64,
+    },
+    Part {
+        transfer: u8,
+        data_size: i32,
+        symbol_size: i32,
+        symbols_count: i32,
+        part: i32,
+        total_size: i64,
+        seqno: i32,
+        data: Vec<u8>,
+    },
+}
+
+// Few transfer IDs make packets hit the same transfers
+fn transfer_id(transfer: u8) -> ton::int256 {
+    ton::int256([transfer % 4; 32])
+}
+
+fn encode(transfer: u8, data: &[u8], lost: u64, ret: &mut Vec<Vec<u8>>) {
+    if data.is_empty() || (data.len() > MAX_ENCODED) {
+        return;
+    }
+    let mut encoder = RaptorqEncoder::with_data(data);
+    let params = encoder.params();
+    let fec_type = FecTypeRaptorQ {
+        data_size: params.data_size,
+        symbol_size: params.symbol_size,
+        symbols_count: params.symbols_count,
+    };
+    for i in 0..params.symbols_count as u32 + 2 {
+        let mut seqno = i;
+        let symbol = encoder.encode(&mut seqno).unwrap();
+        if lost & (1 << (i % 64)) != 0 {
+            continue;
+        }
+        let message = RldpMessagePart {
+            transfer_id: transfer_id(transfer),
+            fec_type: fec_type.clone().into_boxed(),
+            part: 0,
+            total_size: data.len() as i64,
+            seqno: seqno as i32,
+            data: ton::bytes(symbol),
+        }
+        .into_boxed();
+        ret.push(serialize(&message).unwrap())
+    }
+}
+
+fuzz_target!(|packets: Vec<Packet>| {
+    let mut serialized = Vec::new();
+    for packet in packets {
+        match packet {
+            Packet::Complete { transfer, part } => {
+                let message = RldpComplete {
+                    transfer_id: transfer_id(transfer),
+                    part,
+                }
+                .into_boxed();
+                serialized.push(serialize(&message).unwrap())
+            }
+            Packet::Confirm {
+                transfer,
+                part,
+                seqno,
+            } => {
+                let message = RldpConfirm {
+                    transfer_id: transfer_id(transfer),
+                    part,
+                    seqno,
+                }
+                .into_boxed();
+                serialized.push(serialize(&message).unwrap())
+            }
+            Packet::Encoded {
+                transfer,
+                data,
+                lost,
+            } => encode(transfer, &data, lost, &mut serialized),
+            Packet::Part {
+                transfer,
+                data_size,
+                symbol_size,
+                symbols_count,
+                part,
+                total_size,
+                seqno,
+                data,
+            } => {
+                let message = RldpMessagePart {
+                    transfer_id: transfer_id(transfer),
+                    fec_type: FecTypeRaptorQ {
+                        data_size,
+                        symbol_size,
+                        symbols_count,
+                    }
+                    .into_boxed(),
+                    part,
+                    total_size,
+                    seqno,
+                    data: ton::bytes(data),
+                }
+                .into_boxed();
+                serialized.push(serialize(&message).unwrap())
+            }
+        }
+    }
+    rldp_fuzz::run(serialized)
+});
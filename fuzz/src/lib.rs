@@ -0,0 +1,59 @@
+//! Harness for RLDP fuzz targets
+//!
+//! Run with `cargo fuzz run <target> -- -rss_limit_mb=512`, memory limit makes
+//! unbounded allocations driven by packet contents fail the run.
+
+use adnl::common::{AdnlPeers, KeyId, Subscriber};
+use rldp::{RldpNode, RldpNodeOptions, RldpTransport};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use ton_types::Result;
+
+const CLEANUP: u64 = 60000; // Milliseconds, enough for any transfer to time out
+
+/// Transport dropping everything sent by node
+#[derive(Default)]
+pub struct MockTransport {
+    pub sent: AtomicU64,
+}
+
+#[async_trait::async_trait]
+impl RldpTransport for MockTransport {
+    async fn send_custom(&self, _data: &[u8], _peers: &AdnlPeers) -> Result<()> {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Feed packets into fresh node and check transfer table is cleaned up afterwards
+pub fn run(packets: impl IntoIterator<Item = Vec<u8>>) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let node = RldpNode::with_transport(
+            Arc::new(MockTransport::default()),
+            Vec::new(),
+            Vec::new(),
+            RldpNodeOptions {
+                codec_threads: 1,
+                ..Default::default()
+            },
+        );
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        for packet in packets {
+            // Errors are expected, panics are not
+            node.try_consume_custom(&packet, &peers).await.ok();
+        }
+        // Time is paused, so sleep completes as soon as all other tasks are idle
+        tokio::time::sleep(Duration::from_millis(CLEANUP)).await;
+        assert_eq!(
+            node.stats().transfers,
+            0,
+            "RLDP transfers are not cleaned up"
+        );
+    })
+}
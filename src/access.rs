@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use adnl::common::KeyId;
+use ton_api::ton::rldp::message::Query as RldpQuery;
+
+use crate::{RldpNodeOptions, RldpNodeStats, TARGET};
+
+// Authorization of incoming queries
+pub(crate) struct RldpAccess {
+    allowed: Option<std::collections::HashSet<Arc<KeyId>>>,
+    authorizer: Option<Arc<dyn RldpAuthorizer>>,
+    denied: std::collections::HashSet<Arc<KeyId>>,
+    denied_count: AtomicU64,
+}
+
+impl RldpAccess {
+    pub(crate) fn with_options(options: &RldpNodeOptions) -> Self {
+        Self {
+            allowed: options
+                .allowed_peers
+                .as_ref()
+                .map(|peers| peers.iter().cloned().collect()),
+            authorizer: options.authorizer.clone(),
+            denied: options.denied_peers.iter().cloned().collect(),
+            denied_count: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn authorize(&self, peer: &Arc<KeyId>, query: &RldpQuery) -> bool {
+        if !self.authorize_peer(peer) {
+            return false;
+        }
+        match &self.authorizer {
+            Some(authorizer) => match authorizer.authorize(peer, query) {
+                RldpDecision::Allow => true,
+                RldpDecision::Deny => self.deny(peer, "query"),
+            },
+            None => true,
+        }
+    }
+
+    pub(crate) fn authorize_peer(&self, peer: &Arc<KeyId>) -> bool {
+        if self.denied.contains(peer) {
+            return self.deny(peer, "deny list");
+        }
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(peer) {
+                return self.deny(peer, "allow list");
+            }
+        }
+        match &self.authorizer {
+            Some(authorizer) => match authorizer.authorize_peer(peer) {
+                RldpDecision::Allow => true,
+                RldpDecision::Deny => self.deny(peer, "peer"),
+            },
+            None => true,
+        }
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        stats.queries_denied = self.denied_count.load(Ordering::Relaxed);
+    }
+
+    fn deny(&self, peer: &Arc<KeyId>, reason: &str) -> bool {
+        self.denied_count.fetch_add(1, Ordering::Relaxed);
+        log::trace!(
+            target: TARGET,
+            "Incoming RLDP query from {} denied by {} check",
+            peer,
+            reason
+        );
+        false
+    }
+}
+
+/// Authorization decision for incoming query
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RldpDecision {
+    /// Serve query
+    Allow,
+    /// Drop query silently
+    Deny,
+}
+
+/// Authorization hook for incoming queries
+pub trait RldpAuthorizer: std::fmt::Debug + Send + Sync {
+    /// Check peer before receiving query from it
+    fn authorize_peer(&self, _peer: &KeyId) -> RldpDecision {
+        RldpDecision::Allow
+    }
+    /// Check received query before invoking subscribers
+    fn authorize(&self, peer: &KeyId, query: &RldpQuery) -> RldpDecision;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_allow_list() {
+        let (allowed, other) = (KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let access = RldpAccess::with_options(&RldpNodeOptions {
+            allowed_peers: Some(vec![allowed.clone()]),
+            ..Default::default()
+        });
+        assert!(access.authorize_peer(&allowed));
+        assert!(access.authorize(&allowed, &test_query(b"query")));
+        assert!(!access.authorize_peer(&other));
+        assert!(!access.authorize(&other, &test_query(b"query")));
+        let mut stats = RldpNodeStats::default();
+        access.fill_stats(&mut stats);
+        assert_eq!(stats.queries_denied, 2);
+    }
+}
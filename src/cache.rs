@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use adnl::common::{AdnlPeers, KeyId};
+use sha2::Digest;
+use ton_api::ton::rldp::message::Query as RldpQuery;
+
+use crate::{RldpNodeOptions, RldpNodeStats};
+
+pub(crate) type RldpAnswerCacheKey = (Arc<KeyId>, [u8; 32]);
+
+// Recent answers to serve duplicate queries without subscribers
+pub(crate) struct RldpAnswerCache {
+    by_data: bool,
+    entries: std::sync::Mutex<RldpAnswerCacheEntries>,
+    hits: AtomicU64,
+    max_size: usize,
+    misses: AtomicU64,
+    ttl: Duration,
+}
+
+struct RldpAnswerCacheEntries {
+    entries: HashMap<RldpAnswerCacheKey, (Arc<Vec<u8>>, Instant)>,
+    size: usize,
+}
+
+impl RldpAnswerCache {
+    pub(crate) fn with_options(options: &RldpNodeOptions) -> Option<Self> {
+        if options.answer_cache_size == 0 {
+            return None;
+        }
+        let ret = Self {
+            by_data: options.answer_cache_by_data,
+            entries: std::sync::Mutex::new(RldpAnswerCacheEntries {
+                entries: HashMap::new(),
+                size: 0,
+            }),
+            hits: AtomicU64::new(0),
+            max_size: options.answer_cache_size,
+            misses: AtomicU64::new(0),
+            ttl: Duration::from_millis(options.answer_cache_ttl_ms),
+        };
+        Some(ret)
+    }
+
+    pub(crate) fn key(&self, peers: &AdnlPeers, query: &RldpQuery) -> RldpAnswerCacheKey {
+        let mut key = [0u8; 32];
+        if self.by_data {
+            key.copy_from_slice(&sha2::Sha256::digest(&query.data))
+        } else {
+            key.copy_from_slice(&query.query_id.0)
+        }
+        (peers.other().clone(), key)
+    }
+
+    pub(crate) fn get(&self, key: &RldpAnswerCacheKey, now: Instant) -> Option<Arc<Vec<u8>>> {
+        let mut entries = self.entries.lock().ok()?;
+        let answer = match entries.entries.get(key) {
+            Some((answer, expire)) if *expire > now => Some(answer.clone()),
+            Some(_) => {
+                if let Some((answer, _)) = entries.entries.remove(key) {
+                    entries.size -= answer.len()
+                }
+                None
+            }
+            None => None,
+        };
+        if answer.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        answer
+    }
+
+    pub(crate) fn insert(&self, key: RldpAnswerCacheKey, answer: Arc<Vec<u8>>, now: Instant) {
+        let size = answer.len();
+        if size > self.max_size {
+            return;
+        }
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        if let Some((answer, _)) = entries.entries.insert(key, (answer, now + self.ttl)) {
+            entries.size -= answer.len();
+        }
+        entries.size += size;
+        if entries.size <= self.max_size {
+            return;
+        }
+        // Drop expired answers, then the oldest ones
+        let RldpAnswerCacheEntries { entries, size } = &mut *entries;
+        entries.retain(|_, (answer, expire)| {
+            if *expire > now {
+                true
+            } else {
+                *size -= answer.len();
+                false
+            }
+        });
+        while *size > self.max_size {
+            let key = match entries.iter().min_by_key(|(_, (_, expire))| *expire) {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            if let Some((answer, _)) = entries.remove(&key) {
+                *size -= answer.len();
+            }
+        }
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        stats.answer_cache_hits = self.hits.load(Ordering::Relaxed);
+        stats.answer_cache_misses = self.misses.load(Ordering::Relaxed);
+        stats.answer_cache_size = self.size() as u64;
+    }
+
+    fn size(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|entries| entries.size)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::RldpClock;
+
+    fn test_cache(clock: &Arc<TestClock>) -> RldpAnswerCache {
+        RldpAnswerCache::with_options(&RldpNodeOptions {
+            answer_cache_size: 100,
+            answer_cache_ttl_ms: 1000,
+            clock: clock.clone(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn test_key(query_id: u8) -> RldpAnswerCacheKey {
+        (KeyId::from_data([2; 32]), [query_id; 32])
+    }
+
+    #[test]
+    fn test_answer_cache_ttl() {
+        let clock = TestClock::new();
+        let cache = test_cache(&clock);
+        assert!(cache.get(&test_key(1), clock.now()).is_none());
+        cache.insert(test_key(1), Arc::new(vec![1; 10]), clock.now());
+        clock.advance(999);
+        assert_eq!(cache.get(&test_key(1), clock.now()).unwrap().len(), 10);
+        assert!(cache.get(&test_key(2), clock.now()).is_none());
+        clock.advance(1);
+        assert!(cache.get(&test_key(1), clock.now()).is_none());
+        let mut stats = RldpNodeStats::default();
+        cache.fill_stats(&mut stats);
+        assert_eq!(stats.answer_cache_hits, 1);
+        assert_eq!(stats.answer_cache_misses, 3);
+        assert_eq!(stats.answer_cache_size, 0);
+    }
+
+    #[test]
+    fn test_answer_cache_eviction() {
+        let clock = TestClock::new();
+        let cache = test_cache(&clock);
+        // Answer over limit is not cached at all
+        cache.insert(test_key(1), Arc::new(vec![1; 101]), clock.now());
+        assert!(cache.get(&test_key(1), clock.now()).is_none());
+        for i in 1..=3 {
+            cache.insert(test_key(i), Arc::new(vec![i; 40]), clock.now());
+            clock.advance(10);
+        }
+        // Oldest answer is evicted to fit the limit
+        assert!(cache.get(&test_key(1), clock.now()).is_none());
+        assert!(cache.get(&test_key(2), clock.now()).is_some());
+        assert!(cache.get(&test_key(3), clock.now()).is_some());
+        let mut stats = RldpNodeStats::default();
+        cache.fill_stats(&mut stats);
+        assert_eq!(stats.answer_cache_size, 80);
+    }
+}
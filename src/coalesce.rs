@@ -0,0 +1,109 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use adnl::common::{AdnlPeers, KeyId};
+use dashmap::DashMap;
+use sha2::Digest;
+use tokio::sync::watch;
+use ton_types::Result;
+
+use crate::RldpNodeStats;
+
+type RldpCoalesceKey = (Arc<KeyId>, Arc<KeyId>, [u8; 32], Option<i64>);
+type RldpCoalescedReader = watch::Receiver<Option<RldpQueryOutcome>>;
+type RldpQueryOutcome = Arc<std::result::Result<(Option<Vec<u8>>, u64), String>>;
+
+// Same queries in flight share one exchange, sent with ID of the first one
+pub(crate) struct RldpCoalescer {
+    coalesced: AtomicU64,
+    queries: DashMap<RldpCoalesceKey, RldpCoalescedReader>,
+}
+
+impl RldpCoalescer {
+    pub(crate) fn new() -> Self {
+        Self {
+            coalesced: AtomicU64::new(0),
+            queries: DashMap::new(),
+        }
+    }
+
+    // Exchange is run by the leader only, others wait for its outcome
+    pub(crate) async fn query(
+        &self,
+        data: &[u8],
+        max_answer_size: Option<i64>,
+        peers: &AdnlPeers,
+        exchange: impl Future<Output = Result<(Option<Vec<u8>>, u64)>>,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        use dashmap::mapref::entry::Entry;
+
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&sha2::Sha256::digest(data));
+        let key = (
+            peers.local().clone(),
+            peers.other().clone(),
+            hash,
+            max_answer_size,
+        );
+        let sender = loop {
+            match self.queries.entry(key.clone()) {
+                Entry::Occupied(entry) => {
+                    let reader = entry.get().clone();
+                    drop(entry);
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    if let Some(ret) = Self::wait(reader).await {
+                        return ret;
+                    }
+                    // Leader is cancelled, query is run again, maybe as new leader
+                }
+                Entry::Vacant(entry) => {
+                    let (sender, reader) = watch::channel(None);
+                    entry.insert(reader);
+                    break sender;
+                }
+            }
+        };
+        let _guard = RldpCoalescedGuard {
+            key,
+            queries: &self.queries,
+        };
+        let ret = exchange.await;
+        let outcome = match &ret {
+            Ok(answer) => Ok(answer.clone()),
+            Err(e) => Err(e.to_string()),
+        };
+        sender.send(Some(Arc::new(outcome))).ok();
+        ret
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        stats.queries_coalesced = self.coalesced.load(Ordering::Relaxed);
+    }
+
+    // None if leader is cancelled before outcome
+    async fn wait(mut reader: RldpCoalescedReader) -> Option<Result<(Option<Vec<u8>>, u64)>> {
+        loop {
+            let outcome = reader.borrow().clone();
+            if let Some(outcome) = outcome {
+                return Some(match outcome.as_ref() {
+                    Ok(answer) => Ok(answer.clone()),
+                    Err(e) => Err(failure::err_msg(e.clone())),
+                });
+            }
+            reader.changed().await.ok()?;
+        }
+    }
+}
+
+// Removes coalesced query when its leader is done or cancelled
+struct RldpCoalescedGuard<'a> {
+    key: RldpCoalesceKey,
+    queries: &'a DashMap<RldpCoalesceKey, RldpCoalescedReader>,
+}
+
+impl Drop for RldpCoalescedGuard<'_> {
+    fn drop(&mut self) {
+        self.queries.remove(&self.key);
+    }
+}
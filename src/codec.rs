@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use sha2::Digest;
+use tokio::sync::watch;
+use ton_api::ton::fec::type_::RaptorQ as FecTypeRaptorQ;
+use ton_types::{fail, Result};
+
+use crate::runtime::RldpTasks;
+use crate::{RldpBytes, RldpNodeStats, RldpSendTransfer};
+
+/// RaptorQ decoder
+pub struct RaptorqDecoder {
+    engine: raptorq::Decoder,
+    pub(crate) params: FecTypeRaptorQ,
+    received: u32,
+    pub(crate) seqno: u32,
+}
+
+impl RaptorqDecoder {
+    /// Construct with parameter
+    pub fn with_params(params: FecTypeRaptorQ) -> Self {
+        Self {
+            engine: raptorq::Decoder::new(raptorq::ObjectTransmissionInformation::with_defaults(
+                params.data_size as u64,
+                params.symbol_size as u16,
+            )),
+            params,
+            received: 0,
+            seqno: 0,
+        }
+    }
+
+    /// Decode
+    pub fn decode(&mut self, seqno: u32, data: &[u8]) -> Option<Vec<u8>> {
+        self.decode_owned(seqno, data.to_vec())
+    }
+
+    /// Decode taking ownership of symbol data, no copy is made
+    pub fn decode_owned(&mut self, seqno: u32, data: Vec<u8>) -> Option<Vec<u8>> {
+        let packet = raptorq::EncodingPacket::new(raptorq::PayloadId::new(0, seqno), data);
+        self.received += 1;
+        self.seqno = seqno;
+        self.engine.decode(packet)
+    }
+
+    /// Parameters
+    pub fn params(&self) -> &FecTypeRaptorQ {
+        &self.params
+    }
+
+    // Next symbol may trigger the actual (CPU-heavy) decoding
+    pub(crate) fn is_decoding_step(&self) -> bool {
+        self.received + 1 >= self.params.symbols_count as u32
+    }
+}
+
+/// Pool for CPU-heavy RaptorQ jobs, keeps them away from async runtime workers
+pub(crate) struct RaptorqPool {
+    cache: Option<RaptorqEncoderCache>,
+    permits: tokio::sync::Semaphore,
+    tasks: Arc<RldpTasks>,
+}
+
+impl RaptorqPool {
+    const INLINE_SIZE: usize = 64 * 1024; // Smaller data is coded in place, thread hop costs more
+
+    pub(crate) fn with_params(threads: usize, cache_size: usize, tasks: Arc<RldpTasks>) -> Self {
+        let cache = if cache_size > 0 {
+            Some(RaptorqEncoderCache {
+                entries: std::sync::Mutex::new(RaptorqEncoderCacheEntries {
+                    entries: HashMap::new(),
+                    size: 0,
+                    tick: 0,
+                }),
+                hits: AtomicU64::new(0),
+                max_size: cache_size,
+                misses: AtomicU64::new(0),
+                pending: DashMap::new(),
+            })
+        } else {
+            None
+        };
+        Self {
+            cache,
+            permits: tokio::sync::Semaphore::new(std::cmp::max(threads, 1)),
+            tasks,
+        }
+    }
+
+    pub(crate) async fn encoder(&self, data: RldpBytes) -> Result<RaptorqEncoder> {
+        use dashmap::mapref::entry::Entry;
+
+        if data.len() < Self::INLINE_SIZE {
+            return Ok(RaptorqEncoder::with_data(&data));
+        }
+        let cache = match &self.cache {
+            Some(cache) if data.len() >= RaptorqEncoderCache::MIN_DATA_SIZE => cache,
+            _ => return self.run(move || RaptorqEncoder::with_data(&data)).await,
+        };
+        let (key, data) = self
+            .run(move || {
+                let mut key = [0u8; 32];
+                key.copy_from_slice(&sha2::Sha256::digest(&data));
+                (key, data)
+            })
+            .await?;
+        // Same data being encoded concurrently is waited for instead of encoded again
+        let sender = match cache.pending.entry(key) {
+            Entry::Occupied(entry) => {
+                let reader = entry.get().clone();
+                drop(entry);
+                if let Some(prepared) = Self::wait_pending(reader).await {
+                    cache.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(RaptorqEncoder::with_prepared(prepared));
+                }
+                // Encoding was cancelled, so it is done here without cache
+                return self.run(move || RaptorqEncoder::with_data(&data)).await;
+            }
+            Entry::Vacant(entry) => {
+                // Encoded data is cached before its pending entry is removed
+                if let Some(prepared) = cache.get(&key) {
+                    cache.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(RaptorqEncoder::with_prepared(prepared));
+                }
+                let (sender, reader) = watch::channel(None);
+                entry.insert(reader);
+                sender
+            }
+        };
+        let _guard = RaptorqPendingGuard {
+            key,
+            pending: &cache.pending,
+        };
+        cache.misses.fetch_add(1, Ordering::Relaxed);
+        let prepared = self
+            .run(move || Arc::new(RaptorqPrepared::with_data(&data, RldpSendTransfer::SYMBOL)))
+            .await?;
+        cache.insert(key, prepared.clone());
+        sender.send(Some(prepared.clone())).ok();
+        Ok(RaptorqEncoder::with_prepared(prepared))
+    }
+
+    pub(crate) async fn decode(&self, mut job: RaptorqDecodeJob) -> Result<RaptorqDecodeJob> {
+        if (job.decoder.params.data_size as usize) < Self::INLINE_SIZE {
+            job.run();
+            return Ok(job);
+        }
+        self.run(move || {
+            job.run();
+            job
+        })
+        .await
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        if let Some(cache) = &self.cache {
+            stats.encoder_cache_hits = cache.hits.load(Ordering::Relaxed);
+            stats.encoder_cache_misses = cache.misses.load(Ordering::Relaxed);
+            stats.encoder_cache_size = cache.size() as u64;
+        }
+    }
+
+    async fn wait_pending(mut reader: RaptorqPendingReader) -> Option<Arc<RaptorqPrepared>> {
+        loop {
+            if let Some(prepared) = reader.borrow().clone() {
+                return Some(prepared);
+            }
+            reader.changed().await.ok()?;
+        }
+    }
+
+    async fn run<F, R>(&self, job: F) -> Result<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let _permit = self.permits.acquire().await?;
+        match self.tasks.spawn_blocking(job)?.await {
+            Ok(ret) => Ok(ret),
+            Err(e) => fail!("RaptorQ job failed: {}", e),
+        }
+    }
+}
+
+// Prepared encoders by content hash, to send same data to many peers
+struct RaptorqEncoderCache {
+    entries: std::sync::Mutex<RaptorqEncoderCacheEntries>,
+    hits: AtomicU64,
+    max_size: usize,
+    misses: AtomicU64,
+    pending: DashMap<[u8; 32], RaptorqPendingReader>,
+}
+
+type RaptorqPendingReader = watch::Receiver<Option<Arc<RaptorqPrepared>>>;
+
+// Removes pending encoding on completion or cancellation, its waiters are woken up
+struct RaptorqPendingGuard<'a> {
+    key: [u8; 32],
+    pending: &'a DashMap<[u8; 32], RaptorqPendingReader>,
+}
+
+impl Drop for RaptorqPendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.remove(&self.key);
+    }
+}
+
+struct RaptorqEncoderCacheEntries {
+    entries: HashMap<[u8; 32], (Arc<RaptorqPrepared>, u64)>,
+    size: usize,
+    tick: u64,
+}
+
+impl RaptorqEncoderCache {
+    const MIN_DATA_SIZE: usize = 64 * 1024; // Smaller data is cheap to encode
+
+    fn get(&self, key: &[u8; 32]) -> Option<Arc<RaptorqPrepared>> {
+        let mut entries = self.entries.lock().ok()?;
+        entries.tick += 1;
+        let tick = entries.tick;
+        let (prepared, used) = entries.entries.get_mut(key)?;
+        *used = tick;
+        Some(prepared.clone())
+    }
+
+    fn insert(&self, key: [u8; 32], prepared: Arc<RaptorqPrepared>) {
+        let size = prepared.size();
+        if size > self.max_size {
+            return;
+        }
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        entries.tick += 1;
+        let tick = entries.tick;
+        if let Some((prepared, _)) = entries.entries.insert(key, (prepared, tick)) {
+            entries.size -= prepared.size();
+        }
+        entries.size += size;
+        // Evict least recently used
+        while entries.size > self.max_size {
+            let key = match entries.entries.iter().min_by_key(|(_, (_, used))| *used) {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            if let Some((prepared, _)) = entries.entries.remove(&key) {
+                entries.size -= prepared.size();
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|entries| entries.size)
+            .unwrap_or_default()
+    }
+}
+
+/// CPU-heavy decoding step of RLDP transfer, to be run off the event loop
+pub struct RaptorqDecodeJob {
+    pub(crate) data: Vec<u8>,
+    pub(crate) decoded: Option<Vec<u8>>,
+    pub(crate) decoder: RaptorqDecoder,
+    pub(crate) part: i32,
+    pub(crate) seqno: u32,
+}
+
+impl RaptorqDecodeJob {
+    /// Run decoding
+    pub fn run(&mut self) {
+        let data = std::mem::take(&mut self.data);
+        self.decoded = self.decoder.decode_owned(self.seqno, data);
+    }
+}
+
+// Encoder state which does not change while encoding, may be shared
+struct RaptorqPrepared {
+    engine: raptorq::Encoder,
+    params: FecTypeRaptorQ,
+    source_packets: Vec<raptorq::EncodingPacket>,
+}
+
+impl RaptorqPrepared {
+    fn with_data(data: &[u8], symbol_size: usize) -> Self {
+        let engine = raptorq::Encoder::with_defaults(data, symbol_size as u16);
+        let mut source_packets = Vec::new();
+        for encoder in engine.get_block_encoders() {
+            // Reverse order to send efficiently
+            let mut packets = encoder.source_packets();
+            while let Some(packet) = packets.pop() {
+                source_packets.push(packet)
+            }
+        }
+        Self {
+            engine,
+            params: FecTypeRaptorQ {
+                data_size: data.len() as i32,
+                symbol_size: symbol_size as i32,
+                symbols_count: source_packets.len() as i32,
+            },
+            source_packets,
+        }
+    }
+
+    // Approximate memory usage: source packets plus intermediate symbols
+    fn size(&self) -> usize {
+        self.params.data_size as usize * 2
+    }
+}
+
+/// RaptorQ encoded symbol, source symbols are shared with encoder
+pub struct RaptorqSymbol(RaptorqSymbolData);
+
+enum RaptorqSymbolData {
+    Repair(raptorq::EncodingPacket),
+    Source(Arc<RaptorqPrepared>, usize),
+}
+
+impl RaptorqSymbol {
+    /// Symbol data
+    pub fn data(&self) -> &[u8] {
+        match &self.0 {
+            RaptorqSymbolData::Repair(packet) => packet.data(),
+            RaptorqSymbolData::Source(prepared, index) => prepared.source_packets[*index].data(),
+        }
+    }
+}
+
+/// RaptorQ encoder
+#[derive(Clone)]
+pub struct RaptorqEncoder {
+    encoder_index: usize,
+    prepared: Arc<RaptorqPrepared>,
+    source_index: usize,
+}
+
+impl RaptorqEncoder {
+    /// Construct over data
+    pub fn with_data(data: &[u8]) -> Self {
+        Self::with_data_and_symbol_size(data, RldpSendTransfer::SYMBOL)
+    }
+
+    /// Construct over data with custom symbol size
+    pub fn with_data_and_symbol_size(data: &[u8], symbol_size: usize) -> Self {
+        Self::with_prepared(Arc::new(RaptorqPrepared::with_data(data, symbol_size)))
+    }
+
+    fn with_prepared(prepared: Arc<RaptorqPrepared>) -> Self {
+        Self {
+            encoder_index: 0,
+            source_index: prepared.source_packets.len(),
+            prepared,
+        }
+    }
+
+    /// Encode
+    pub fn encode(&mut self, seqno: &mut u32) -> Result<Vec<u8>> {
+        Ok(self.encode_symbol(seqno)?.data().to_vec())
+    }
+
+    /// Encode without copying symbol data
+    pub fn encode_symbol(&mut self, seqno: &mut u32) -> Result<RaptorqSymbol> {
+        if self.source_index > 0 {
+            self.source_index -= 1;
+            let packet = &self.prepared.source_packets[self.source_index];
+            *seqno = packet.payload_id().encoding_symbol_id();
+            return Ok(RaptorqSymbol(RaptorqSymbolData::Source(
+                self.prepared.clone(),
+                self.source_index,
+            )));
+        }
+        let encoders = self.prepared.engine.get_block_encoders();
+        let mut packets = encoders[self.encoder_index].repair_packets(*seqno, 1);
+        let packet = if let Some(packet) = packets.pop() {
+            packet
+        } else {
+            fail!("INTERNAL ERROR: cannot encode repair packet");
+        };
+        self.encoder_index += 1;
+        if self.encoder_index >= encoders.len() {
+            self.encoder_index = 0;
+        }
+        *seqno = packet.payload_id().encoding_symbol_id();
+        Ok(RaptorqSymbol(RaptorqSymbolData::Repair(packet)))
+    }
+
+    /// Parameters
+    pub fn params(&self) -> &FecTypeRaptorQ {
+        &self.prepared.params
+    }
+
+    pub(crate) fn has_source_packets(&self) -> bool {
+        self.source_index > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::testing::*;
+    use crate::{RldpRecvTransfer, RldpTransferStatus};
+
+    #[tokio::test]
+    async fn test_concurrent_encoders_shared() {
+        let pool = RaptorqPool::with_params(4, 64 * 1024 * 1024, Arc::new(RldpTasks::new()));
+        let data = test_data(RaptorqEncoderCache::MIN_DATA_SIZE * 2);
+        let (first, second, third) = tokio::join!(
+            pool.encoder(data.clone().into()),
+            pool.encoder(data.clone().into()),
+            pool.encoder(data.clone().into())
+        );
+        for encoder in [first, second, third] {
+            assert_eq!(encoder.unwrap().params().data_size as usize, data.len())
+        }
+        let cache = pool.cache.as_ref().unwrap();
+        assert_eq!(cache.misses.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 2);
+        assert!(cache.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_small_data_coded_inline() {
+        let pool = RaptorqPool::with_params(1, 0, Arc::new(RldpTasks::new()));
+        // Pool is busy, so only data coded in place gets through
+        let _permit = pool.permits.acquire().await.unwrap();
+        let wait = Duration::from_secs(5);
+        let data = test_data(100);
+        let encoder = tokio::time::timeout(wait, pool.encoder(data.clone().into()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(encoder.params().symbols_count, 1);
+        let now = Instant::now();
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        let msg = part_packets(&data, 0, data.len()).remove(0);
+        let job = recv.handle_packet(msg, now).unwrap().unwrap();
+        let job = tokio::time::timeout(wait, pool.decode(job))
+            .await
+            .unwrap()
+            .unwrap();
+        recv.complete_job(job, now).unwrap();
+        assert_eq!(recv.status(), RldpTransferStatus::Done);
+        assert_eq!(recv.take_data(), data);
+        // Big data waits for pool
+        let data = test_data(RaptorqPool::INLINE_SIZE);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), pool.encoder(data.into()))
+                .await
+                .is_err()
+        );
+    }
+}
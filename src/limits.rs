@@ -0,0 +1,654 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use adnl::common::KeyId;
+use dashmap::DashMap;
+
+use crate::{RldpNodeOptions, RldpNodeStats, TARGET};
+
+// Penalty scores of misbehaving peers, peers over threshold are banned
+pub(crate) struct RldpPenalties {
+    ban_duration: Duration,
+    bans: DashMap<Arc<KeyId>, Instant>,
+    scores: DashMap<Arc<KeyId>, RldpPenaltyScore>,
+    threshold: u32,
+}
+
+impl RldpPenalties {
+    const DECAY: u64 = 1000; // Milliseconds per point
+    const MAX_PEERS: usize = 4096; // Decayed scores are pruned above
+    pub(crate) const PACKET: u32 = 1; // Points for malformed packet
+    pub(crate) const QUERY: u32 = 10; // Points for malformed query
+
+    pub(crate) fn with_options(options: &RldpNodeOptions) -> Self {
+        Self {
+            ban_duration: Duration::from_millis(options.ban_duration_ms),
+            bans: DashMap::new(),
+            scores: DashMap::new(),
+            threshold: options.ban_threshold,
+        }
+    }
+
+    // Banned peers with remaining ban time
+    pub(crate) fn banned(&self, now: Instant) -> Vec<(Arc<KeyId>, Duration)> {
+        self.bans
+            .iter()
+            .filter_map(|ban| {
+                let left = ban.value().checked_duration_since(now)?;
+                Some((ban.key().clone(), left))
+            })
+            .collect()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.scores.clear();
+        self.bans.clear()
+    }
+
+    pub(crate) fn is_banned(&self, peer: &Arc<KeyId>, now: Instant) -> bool {
+        if let Some(until) = self.bans.get(peer) {
+            if *until > now {
+                return true;
+            }
+        }
+        self.bans.remove_if(peer, |_, until| *until <= now);
+        false
+    }
+
+    pub(crate) fn penalize(&self, peer: &Arc<KeyId>, points: u32, reason: &str, now: Instant) {
+        if self.threshold == 0 {
+            return;
+        }
+        if self.scores.len() > Self::MAX_PEERS {
+            self.scores.retain(|_, score| score.decay(now) > 0)
+        }
+        let mut score = self
+            .scores
+            .entry(peer.clone())
+            .or_insert_with(|| RldpPenaltyScore {
+                points: 0,
+                updated: now,
+            });
+        let points = score.decay(now) + points;
+        score.points = points;
+        drop(score);
+        log::debug!(
+            target: TARGET,
+            "RLDP peer {} penalized ({} points): {}",
+            peer,
+            points,
+            reason
+        );
+        if points >= self.threshold {
+            self.scores.remove(peer);
+            if self.bans.len() > Self::MAX_PEERS {
+                self.bans.retain(|_, until| *until > now)
+            }
+            self.bans.insert(peer.clone(), now + self.ban_duration);
+            log::warn!(
+                target: TARGET,
+                "RLDP peer {} banned for {} ms: {}",
+                peer,
+                self.ban_duration.as_millis(),
+                reason
+            )
+        }
+    }
+
+    // Lifts ban and resets score, false if peer is not banned
+    pub(crate) fn unban(&self, peer: &Arc<KeyId>) -> bool {
+        self.scores.remove(peer);
+        self.bans.remove(peer).is_some()
+    }
+}
+
+struct RldpPenaltyScore {
+    points: u32,
+    updated: Instant,
+}
+
+impl RldpPenaltyScore {
+    // Points left after linear decay, fraction of point is kept for next decay
+    fn decay(&mut self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.updated).as_millis() as u64;
+        let decayed = std::cmp::min(elapsed / RldpPenalties::DECAY, self.points as u64);
+        self.points -= decayed as u32;
+        if self.points == 0 {
+            self.updated = now
+        } else {
+            self.updated += Duration::from_millis(decayed * RldpPenalties::DECAY)
+        }
+        self.points
+    }
+}
+
+// Limits of concurrent incoming transfers, zero means no limit
+pub(crate) struct RldpIncoming {
+    count: AtomicUsize,
+    max: usize,
+    max_per_peer: usize,
+    peers: DashMap<Arc<KeyId>, usize>,
+    refused: AtomicU64,
+}
+
+impl RldpIncoming {
+    pub(crate) fn with_options(options: &RldpNodeOptions) -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            max: options.max_incoming_transfers,
+            max_per_peer: options.max_incoming_transfers_per_peer,
+            peers: DashMap::new(),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn acquire(self: &Arc<Self>, peer: &Arc<KeyId>) -> Option<RldpIncomingGuard> {
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+        if (self.max > 0) && (count >= self.max) {
+            self.count.fetch_sub(1, Ordering::Relaxed);
+            return self.refuse(peer, "total");
+        }
+        let mut count = self.peers.entry(peer.clone()).or_insert(0);
+        if (self.max_per_peer > 0) && (*count >= self.max_per_peer) {
+            drop(count);
+            self.count.fetch_sub(1, Ordering::Relaxed);
+            return self.refuse(peer, "peer");
+        }
+        *count += 1;
+        Some(RldpIncomingGuard {
+            incoming: self.clone(),
+            peer: peer.clone(),
+        })
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        stats.incoming_transfers = self.count.load(Ordering::Relaxed) as u64;
+        stats.incoming_transfers_refused = self.refused.load(Ordering::Relaxed);
+    }
+
+    fn refuse(&self, peer: &Arc<KeyId>, limit: &str) -> Option<RldpIncomingGuard> {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        log::trace!(
+            target: TARGET,
+            "Incoming RLDP transfer from {} refused by {} limit",
+            peer,
+            limit
+        );
+        None
+    }
+
+    fn release(&self, peer: &Arc<KeyId>) {
+        use dashmap::mapref::entry::Entry;
+
+        if let Entry::Occupied(mut entry) = self.peers.entry(peer.clone()) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Load of answering side, new queries are shed when overloaded
+pub(crate) struct RldpLoad {
+    answers: AtomicUsize,
+    bytes: AtomicU64,
+    latency: AtomicU64,
+    max_answers: usize,
+    max_bytes: u64,
+    max_latency: u64,
+    shed: AtomicU64,
+}
+
+impl RldpLoad {
+    pub(crate) fn with_options(options: &RldpNodeOptions) -> Self {
+        Self {
+            answers: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            latency: AtomicU64::new(0),
+            max_answers: options.max_answers_in_flight,
+            max_bytes: options.max_answer_bytes_in_flight,
+            max_latency: options.max_subscriber_latency_ms,
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    // Check before receiving query
+    pub(crate) fn admit(&self, peer: &Arc<KeyId>) -> bool {
+        let answers = self.answers.load(Ordering::Relaxed);
+        if (self.max_answers > 0) && (answers >= self.max_answers) {
+            return self.shed(peer, "answers in flight");
+        }
+        if (self.max_bytes > 0) && (self.bytes.load(Ordering::Relaxed) >= self.max_bytes) {
+            return self.shed(peer, "answer bytes in flight");
+        }
+        // Latency estimate is refreshed only by answered queries, so one is always let in
+        if (self.max_latency > 0)
+            && (answers > 0)
+            && (self.latency.load(Ordering::Relaxed) >= self.max_latency)
+        {
+            return self.shed(peer, "subscriber latency");
+        }
+        true
+    }
+
+    // Check before invoking subscribers, query is not answered if it would miss deadline
+    pub(crate) fn admit_query(&self, peer: &Arc<KeyId>, deadline: Instant, now: Instant) -> bool {
+        if self.max_latency == 0 {
+            return true;
+        }
+        let latency = self.latency.load(Ordering::Relaxed);
+        let left = deadline.saturating_duration_since(now).as_millis() as u64;
+        if left < latency {
+            return self.shed(peer, "query deadline");
+        }
+        true
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        stats.answers_in_flight = self.answers.load(Ordering::Relaxed) as u64;
+        stats.answer_bytes_in_flight = self.bytes.load(Ordering::Relaxed);
+        stats.subscriber_latency_ms = self.latency.load(Ordering::Relaxed);
+        stats.queries_shed = self.shed.load(Ordering::Relaxed);
+    }
+
+    fn shed(&self, peer: &Arc<KeyId>, reason: &str) -> bool {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+        log::trace!(
+            target: TARGET,
+            "Incoming RLDP query from {} shed by {}",
+            peer,
+            reason
+        );
+        false
+    }
+
+    pub(crate) fn start(self: &Arc<Self>) -> RldpLoadGuard {
+        self.answers.fetch_add(1, Ordering::Relaxed);
+        RldpLoadGuard {
+            bytes: 0,
+            load: self.clone(),
+        }
+    }
+
+    // Exponential moving average of subscriber processing time
+    pub(crate) fn update_latency(&self, elapsed: Duration) {
+        let elapsed = elapsed.as_millis() as u64;
+        self.latency
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |latency| {
+                Some((latency * 7 + elapsed) / 8)
+            })
+            .ok();
+    }
+}
+
+// Answer in flight, released on drop
+pub(crate) struct RldpLoadGuard {
+    bytes: u64,
+    load: Arc<RldpLoad>,
+}
+
+impl RldpLoadGuard {
+    pub(crate) fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.load.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RldpLoadGuard {
+    fn drop(&mut self) {
+        self.load.bytes.fetch_sub(self.bytes, Ordering::Relaxed);
+        self.load.answers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Rate limits of served answers
+pub(crate) struct RldpAnswerLimits {
+    bytes: RldpRateLimit,
+    queries: RldpRateLimit,
+    rejected: AtomicU64,
+}
+
+impl RldpAnswerLimits {
+    pub(crate) fn with_options(options: &RldpNodeOptions) -> Self {
+        let now = options.clock.now();
+        Self {
+            bytes: RldpRateLimit::new(
+                options.answer_bytes_per_sec,
+                options.answer_bytes_per_sec_per_peer,
+                now,
+            ),
+            queries: RldpRateLimit::new(
+                options.queries_per_sec,
+                options.queries_per_sec_per_peer,
+                now,
+            ),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    // Check before receiving query, so rejected one costs no transfer
+    pub(crate) fn admit(&self, peer: &Arc<KeyId>, now: Instant) -> bool {
+        if self.queries.try_take(peer, 1, now) {
+            return true;
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        log::trace!(
+            target: TARGET,
+            "Incoming RLDP query from {} rejected by rate limit",
+            peer
+        );
+        false
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        stats.queries_rejected = self.rejected.load(Ordering::Relaxed);
+    }
+
+    // Delay to keep answer bytes within limits
+    pub(crate) fn reserve_bytes(&self, peer: &Arc<KeyId>, amount: u64, now: Instant) -> Duration {
+        self.bytes.reserve(peer, amount, now)
+    }
+}
+
+// Token bucket limits globally and per peer, zero rate means no limit
+struct RldpRateLimit {
+    global: Option<std::sync::Mutex<RldpTokenBucket>>,
+    peer_rate: u64,
+    peers: DashMap<Arc<KeyId>, RldpTokenBucket>,
+}
+
+impl RldpRateLimit {
+    const MAX_PEERS: usize = 4096; // Idle peers are pruned above
+
+    fn new(rate: u64, peer_rate: u64, now: Instant) -> Self {
+        let global = if rate > 0 {
+            Some(std::sync::Mutex::new(RldpTokenBucket::new(rate, now)))
+        } else {
+            None
+        };
+        Self {
+            global,
+            peer_rate,
+            peers: DashMap::new(),
+        }
+    }
+
+    // Delay to keep sending within limits, amount is taken in advance
+    fn reserve(&self, peer: &Arc<KeyId>, amount: u64, now: Instant) -> Duration {
+        let mut delay = Duration::from_millis(0);
+        if let Some(global) = &self.global {
+            if let Ok(mut global) = global.lock() {
+                delay = global.reserve(amount, now)
+            }
+        }
+        if self.peer_rate > 0 {
+            let peer = self.peer(peer, now).reserve(amount, now);
+            delay = std::cmp::max(delay, peer)
+        }
+        delay
+    }
+
+    fn try_take(&self, peer: &Arc<KeyId>, amount: u64, now: Instant) -> bool {
+        if self.peer_rate > 0 && !self.peer(peer, now).try_take(amount, now) {
+            return false;
+        }
+        if let Some(global) = &self.global {
+            if let Ok(mut global) = global.lock() {
+                if !global.try_take(amount, now) {
+                    if self.peer_rate > 0 {
+                        self.peer(peer, now).refund(amount)
+                    }
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn peer(
+        &self,
+        peer: &Arc<KeyId>,
+        now: Instant,
+    ) -> dashmap::mapref::one::RefMut<'_, Arc<KeyId>, RldpTokenBucket> {
+        if self.peers.len() > Self::MAX_PEERS {
+            self.peers.retain(|_, bucket| !bucket.is_full(now))
+        }
+        self.peers
+            .entry(peer.clone())
+            .or_insert_with(|| RldpTokenBucket::new(self.peer_rate, now))
+    }
+}
+
+// Tokens are refilled at rate per second up to one second burst
+struct RldpTokenBucket {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RldpTokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate as f64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * self.rate as f64, self.rate as f64);
+        self.updated = std::cmp::max(self.updated, now);
+    }
+
+    fn refund(&mut self, amount: u64) {
+        self.tokens += amount as f64
+    }
+
+    // Tokens may go into debt, which is to be waited out
+    fn reserve(&mut self, amount: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_millis(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+
+    fn try_take(&mut self, amount: u64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < amount as f64 {
+            return false;
+        }
+        self.tokens -= amount as f64;
+        true
+    }
+}
+
+// Slot of incoming transfer, released on drop
+pub(crate) struct RldpIncomingGuard {
+    incoming: Arc<RldpIncoming>,
+    peer: Arc<KeyId>,
+}
+
+impl Drop for RldpIncomingGuard {
+    fn drop(&mut self) {
+        self.incoming.release(&self.peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::RldpClock;
+
+    #[test]
+    fn test_token_bucket_reserve() {
+        let now = Instant::now();
+        let mut bucket = RldpTokenBucket::new(1000, now);
+        assert_eq!(bucket.reserve(600, now), Duration::from_millis(0));
+        // Debt is to be waited out at rate
+        assert_eq!(bucket.reserve(900, now), Duration::from_millis(500));
+        assert_eq!(bucket.tokens, -500.0);
+        let later = now + Duration::from_millis(250);
+        assert_eq!(bucket.reserve(0, later), Duration::from_millis(250));
+        // Refill is capped by one second burst
+        let later = later + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(0, later), Duration::from_millis(0));
+        assert_eq!(bucket.tokens, 1000.0);
+        assert!(bucket.is_full(later));
+    }
+
+    #[test]
+    fn test_token_bucket_take() {
+        let now = Instant::now();
+        let mut bucket = RldpTokenBucket::new(10, now);
+        assert!(bucket.try_take(10, now));
+        // No debt is taken
+        assert!(!bucket.try_take(1, now));
+        assert_eq!(bucket.tokens, 0.0);
+        assert!(bucket.try_take(1, now + Duration::from_millis(100)));
+        bucket.refund(1);
+        assert!(bucket.try_take(1, now + Duration::from_millis(100)));
+        assert!(!bucket.try_take(1, now + Duration::from_millis(100)));
+        // Peer token is refunded when global bucket is empty
+        let peer = KeyId::from_data([1; 32]);
+        let limit = RldpRateLimit::new(1, 2, now);
+        assert!(limit.try_take(&peer, 1, now));
+        assert!(!limit.try_take(&peer, 1, now));
+        assert_eq!(limit.peers.get(&peer).unwrap().tokens, 1.0);
+    }
+
+    #[test]
+    fn test_penalty_decay() {
+        let now = Instant::now();
+        let mut score = RldpPenaltyScore {
+            points: 5,
+            updated: now,
+        };
+        let decay = |millis| now + Duration::from_millis(millis);
+        assert_eq!(score.decay(decay(999)), 5);
+        // Fraction of point is kept for next decay
+        assert_eq!(score.decay(decay(2500)), 3);
+        assert_eq!(score.updated, decay(2000));
+        assert_eq!(score.decay(decay(3000)), 2);
+        assert_eq!(score.decay(decay(60000)), 0);
+        assert_eq!(score.updated, decay(60000));
+    }
+
+    #[test]
+    fn test_load_shedding() {
+        let clock = TestClock::new();
+        let load = RldpLoad::with_options(&RldpNodeOptions {
+            max_subscriber_latency_ms: 100,
+            ..Default::default()
+        });
+        let peer = KeyId::from_data([1; 32]);
+        for _ in 0..20 {
+            load.update_latency(Duration::from_millis(400))
+        }
+        let mut stats = RldpNodeStats::default();
+        load.fill_stats(&mut stats);
+        assert!((300..400).contains(&stats.subscriber_latency_ms));
+        // Remaining time is measured on local clock from receive time
+        let deadline = clock.now() + Duration::from_secs(1);
+        assert!(load.admit_query(&peer, deadline, clock.now()));
+        clock.advance(600);
+        assert!(load.admit_query(&peer, deadline, clock.now()));
+        clock.advance(100);
+        assert!(!load.admit_query(&peer, deadline, clock.now()));
+        clock.advance(1000);
+        assert!(!load.admit_query(&peer, deadline, clock.now()));
+        load.fill_stats(&mut stats);
+        assert_eq!(stats.queries_shed, 2);
+    }
+
+    #[test]
+    fn test_penalty_ban() {
+        let clock = TestClock::new();
+        let penalties = RldpPenalties::with_options(&RldpNodeOptions {
+            ban_threshold: 10,
+            ban_duration_ms: 5000,
+            clock: clock.clone(),
+            ..Default::default()
+        });
+        let (peer, other) = (KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        penalties.penalize(&peer, RldpPenalties::QUERY - 1, "test", clock.now());
+        penalties.penalize(&other, RldpPenalties::QUERY - 1, "test", clock.now());
+        // Decayed points do not reach threshold
+        clock.advance(RldpPenalties::DECAY);
+        penalties.penalize(&peer, RldpPenalties::PACKET, "test", clock.now());
+        assert!(!penalties.is_banned(&peer, clock.now()));
+        penalties.penalize(&peer, RldpPenalties::PACKET, "test", clock.now());
+        assert!(penalties.is_banned(&peer, clock.now()));
+        assert!(penalties.scores.get(&peer).is_none());
+        assert_eq!(penalties.banned(clock.now()).len(), 1);
+        // Ban expires
+        clock.advance(4999);
+        assert!(penalties.is_banned(&peer, clock.now()));
+        clock.advance(1);
+        assert!(!penalties.is_banned(&peer, clock.now()));
+        assert!(penalties.bans.is_empty());
+        assert!(penalties.banned(clock.now()).is_empty());
+        // Unban lifts ban and resets score
+        penalties.penalize(&peer, RldpPenalties::QUERY, "test", clock.now());
+        assert!(penalties.unban(&peer));
+        assert!(!penalties.is_banned(&peer, clock.now()));
+        assert!(!penalties.unban(&peer));
+        penalties.penalize(&other, RldpPenalties::PACKET, "test", clock.now());
+        assert!(penalties.scores.get(&other).is_some());
+        assert!(!penalties.is_banned(&other, clock.now()));
+        penalties.penalize(&peer, RldpPenalties::QUERY, "test", clock.now());
+        penalties.clear();
+        assert!(penalties.bans.is_empty());
+        assert!(penalties.scores.is_empty());
+        // Score starts from scratch after reset
+        penalties.penalize(&other, RldpPenalties::QUERY - 1, "test", clock.now());
+        assert!(!penalties.is_banned(&other, clock.now()));
+    }
+
+    fn test_incoming(max: usize, max_per_peer: usize) -> Arc<RldpIncoming> {
+        Arc::new(RldpIncoming::with_options(&RldpNodeOptions {
+            max_incoming_transfers: max,
+            max_incoming_transfers_per_peer: max_per_peer,
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn test_incoming_limits() {
+        let (first, second) = (KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let incoming = test_incoming(3, 2);
+        let guards = vec![
+            incoming.acquire(&first).unwrap(),
+            incoming.acquire(&first).unwrap(),
+        ];
+        assert!(incoming.acquire(&first).is_none());
+        let guard = incoming.acquire(&second).unwrap();
+        assert!(incoming.acquire(&second).is_none());
+        assert_eq!(incoming.refused.load(Ordering::Relaxed), 2);
+        // Released slot is taken again
+        drop(guard);
+        assert!(incoming.acquire(&second).is_some());
+        drop(guards);
+        assert_eq!(incoming.count.load(Ordering::Relaxed), 0);
+        assert!(incoming.peers.is_empty());
+        // Zero means no limit
+        let incoming = test_incoming(0, 0);
+        let guards = (0..100)
+            .map(|_| incoming.acquire(&first).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(incoming.count.load(Ordering::Relaxed), guards.len());
+        assert_eq!(incoming.refused.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use adnl::common::{AdnlPeers, QueryId};
+
+use crate::RldpSendSource;
+
+/// RLDP query passed through middleware
+#[derive(Clone, Debug)]
+pub struct RldpQueryInfo {
+    /// Query data, may be rewritten before query is sent or processed
+    pub data: Vec<u8>,
+    /// Query deadline, Unix time
+    pub deadline: i32,
+    /// Query is served by this node
+    pub incoming: bool,
+    /// Answer size limit, may be rewritten for outgoing query
+    pub max_answer_size: i64,
+    /// Peers of query
+    pub peers: AdnlPeers,
+    /// Query ID, coalesced outgoing queries are sent with ID of the first one
+    pub query_id: QueryId,
+}
+
+/// Outcome of RLDP query passed through middleware
+pub enum RldpOutcome {
+    /// Answer data
+    Answer(Vec<u8>),
+    /// Answer data read from source, for served queries only
+    Source(Box<dyn RldpSendSource>),
+    /// Answer data passed to stream reader, for streamed outgoing queries only
+    Streamed,
+    /// No answer
+    Empty,
+    /// Query failed
+    Error(failure::Error),
+}
+
+/// Middleware wrapping outgoing and served RLDP queries
+#[async_trait::async_trait]
+pub trait RldpMiddleware: std::fmt::Debug + Send + Sync {
+    /// Called in chain order before query is sent or processed, outcome stops the chain
+    async fn before(&self, _query: &mut RldpQueryInfo) -> Option<RldpOutcome> {
+        None
+    }
+    /// Called in reverse chain order with outcome, for middleware passed before
+    async fn after(&self, _query: &RldpQueryInfo, _outcome: &mut RldpOutcome) {}
+}
+
+// Middleware in chain order, outcome goes back through middleware passed by query
+pub(crate) struct RldpMiddlewareChain(Vec<Arc<dyn RldpMiddleware>>);
+
+impl RldpMiddlewareChain {
+    pub(crate) fn new(middleware: Vec<Arc<dyn RldpMiddleware>>) -> Self {
+        Self(middleware)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Returns number of passed middleware and outcome if chain is stopped
+    pub(crate) async fn before(&self, query: &mut RldpQueryInfo) -> (usize, Option<RldpOutcome>) {
+        for (passed, middleware) in self.0.iter().enumerate() {
+            if let Some(outcome) = middleware.before(query).await {
+                return (passed + 1, Some(outcome));
+            }
+        }
+        (self.0.len(), None)
+    }
+
+    pub(crate) async fn after(
+        &self,
+        passed: usize,
+        query: &RldpQueryInfo,
+        mut outcome: RldpOutcome,
+    ) -> RldpOutcome {
+        for middleware in self.0[..passed].iter().rev() {
+            middleware.after(query, &mut outcome).await
+        }
+        outcome
+    }
+}
@@ -0,0 +1,1786 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use adnl::{common::*, node::AdnlNode};
+use dashmap::DashMap;
+use tokio::sync::{mpsc, watch};
+use ton_api::ton::rldp::message::Query as RldpQuery;
+use ton_api::ton::rldp::messagepart::Complete as RldpComplete;
+use ton_api::ton::rldp::messagepart::Confirm as RldpConfirm;
+use ton_api::ton::rldp::messagepart::MessagePart as RldpMessagePart;
+use ton_api::ton::rldp::Message as RldpMessageBoxed;
+use ton_api::ton::rldp::MessagePart as RldpMessagePartBoxed;
+use ton_api::{ton, IntoBoxed};
+use ton_types::{fail, Result};
+
+use crate::access::RldpAccess;
+use crate::cache::RldpAnswerCache;
+use crate::coalesce::RldpCoalescer;
+use crate::codec::RaptorqPool;
+use crate::limits::{RldpAnswerLimits, RldpIncoming, RldpIncomingGuard, RldpLoad, RldpPenalties};
+use crate::middleware::RldpMiddlewareChain;
+use crate::runtime::RldpTasks;
+use crate::serve::RldpQueryServer;
+use crate::subscribers::RldpSubscribers;
+use crate::table::{RldpTransfer, RldpTransferQueue, RldpTransfers};
+use crate::tl::fill_tl_bytes;
+use crate::transfer::RldpTimer;
+use crate::{
+    RldpAnswerData, RldpAnswerStream, RldpBytes, RldpClock, RldpNodeOptions, RldpOutcome,
+    RldpQueryInfo, RldpRecvTransfer, RldpRng, RldpSendSource, RldpSendTransfer,
+    RldpSourceSubscriber, RldpSubscriberHandle, RldpSubscriberStats, RldpTransferStatus,
+    RldpTransport, TransferId, TARGET,
+};
+
+// IO side of transfer driven by RldpNode
+struct RldpContext {
+    buf: Vec<u8>,
+    cancel: RldpNodeState,
+    clock: Arc<dyn RldpClock>,
+    limits: Option<Arc<RldpAnswerLimits>>,
+    lookahead: bool,
+    peers: AdnlPeers,
+    penalties: Arc<RldpPenalties>,
+    pool: Arc<RaptorqPool>,
+    queue_reader: mpsc::Receiver<RldpMessagePartBoxed>,
+    state: watch::Receiver<RldpNodeState>,
+    transport: Arc<dyn RldpTransport>,
+}
+
+// Node lifecycle, transfers are cancelled when state reaches their level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum RldpNodeState {
+    Running,
+    Draining,
+    Stopped,
+}
+
+struct RldpPeer {
+    queries: AtomicU32,
+    queue: lockfree::queue::Queue<Arc<tokio::sync::Barrier>>,
+}
+
+/// Rldp Node statistics
+#[derive(Clone, Debug, Default)]
+pub struct RldpNodeStats {
+    /// Encoders taken from cache
+    pub encoder_cache_hits: u64,
+    /// Encoders prepared from scratch while cache is enabled
+    pub encoder_cache_misses: u64,
+    /// Memory taken by cached encoders, bytes
+    pub encoder_cache_size: u64,
+    /// Queries answered from cache
+    pub answer_cache_hits: u64,
+    /// Queries not found in answer cache
+    pub answer_cache_misses: u64,
+    /// Memory taken by cached answers, bytes
+    pub answer_cache_size: u64,
+    /// Queries which joined identical query in flight
+    pub queries_coalesced: u64,
+    /// Entries in transfer table
+    pub transfers: u64,
+    /// Stuck transfers expired by janitor
+    pub transfers_expired: u64,
+    /// Packets of any kind dropped on full transfer queue
+    pub packets_dropped: u64,
+    /// Incoming transfers in progress
+    pub incoming_transfers: u64,
+    /// Attempts to start incoming transfer refused by limits
+    pub incoming_transfers_refused: u64,
+    /// Incoming packets and queries rejected by rate limits
+    pub queries_rejected: u64,
+    /// Incoming packets and queries denied by authorization
+    pub queries_denied: u64,
+    /// Peers banned for misbehavior
+    pub peers_banned: u64,
+    /// Answers being prepared or sent
+    pub answers_in_flight: u64,
+    /// Bytes of answers being sent
+    pub answer_bytes_in_flight: u64,
+    /// Average subscriber latency, milliseconds
+    pub subscriber_latency_ms: u64,
+    /// Incoming packets and queries shed on overload
+    pub queries_shed: u64,
+    /// Statistics of subscribers in order of registration
+    pub subscribers: Vec<RldpSubscriberStats>,
+    /// Statistics of source subscribers in order of registration
+    pub source_subscribers: Vec<RldpSubscriberStats>,
+    /// Statistics of routed query handlers by TL constructor ID of query
+    pub routes: BTreeMap<u32, RldpSubscriberStats>,
+    /// Queries not consumed by any handler or subscriber
+    pub queries_unhandled: u64,
+}
+
+/// Rldp Node
+pub struct RldpNode {
+    access: Arc<RldpAccess>,
+    answer_cache: Option<Arc<RldpAnswerCache>>,
+    clock: Arc<dyn RldpClock>,
+    coalescer: Option<RldpCoalescer>,
+    incoming: Arc<RldpIncoming>,
+    limits: Arc<RldpAnswerLimits>,
+    load: Arc<RldpLoad>,
+    lookahead: bool,
+    middleware: Arc<RldpMiddlewareChain>,
+    peers: DashMap<Arc<KeyId>, Arc<RldpPeer>>,
+    penalties: Arc<RldpPenalties>,
+    pool: Arc<RaptorqPool>,
+    rng: Arc<dyn RldpRng>,
+    spill_dir: std::path::PathBuf,
+    state: watch::Sender<RldpNodeState>,
+    state_reader: watch::Receiver<RldpNodeState>,
+    subscribers: std::sync::RwLock<Arc<RldpSubscribers>>,
+    tasks: std::sync::Mutex<Option<mpsc::Sender<()>>>,
+    tasks_done: tokio::sync::Mutex<mpsc::Receiver<()>>,
+    transfers: Arc<RldpTransfers>,
+    transport: Arc<dyn RldpTransport>,
+    workers: Arc<RldpTasks>,
+}
+
+impl RldpNode {
+    const ANSWER_OVERHEAD: usize = 48; // TL constructor, query ID, data length and padding
+    const MAX_ANSWER_SIZE: i64 = 128 * 1024; // Default
+    const MAX_QUERIES: u32 = 3;
+
+    /// Constructor
+    pub fn with_adnl_node(adnl: Arc<AdnlNode>, subscribers: Vec<Arc<dyn Subscriber>>) -> Arc<Self> {
+        Self::with_adnl_node_and_options(adnl, subscribers, Vec::new(), RldpNodeOptions::default())
+    }
+
+    /// Constructor with options
+    pub fn with_adnl_node_and_options(
+        adnl: Arc<AdnlNode>,
+        subscribers: Vec<Arc<dyn Subscriber>>,
+        source_subscribers: Vec<Arc<dyn RldpSourceSubscriber>>,
+        options: RldpNodeOptions,
+    ) -> Arc<Self> {
+        Self::with_transport(adnl, subscribers, source_subscribers, options)
+    }
+
+    /// Constructor over custom transport
+    pub fn with_transport(
+        transport: Arc<dyn RldpTransport>,
+        subscribers: Vec<Arc<dyn Subscriber>>,
+        source_subscribers: Vec<Arc<dyn RldpSourceSubscriber>>,
+        options: RldpNodeOptions,
+    ) -> Arc<Self> {
+        let (state, state_reader) = watch::channel(RldpNodeState::Running);
+        // Spawned tasks hold sender clones, so channel closes when all of them finish
+        let (tasks, tasks_done) = mpsc::channel(1);
+        // Subscriber calls and codec jobs of node tasks, stopped with them
+        let workers = Arc::new(RldpTasks::new());
+        let mut registered = RldpSubscribers::with_options(&options, workers.clone());
+        for subscriber in subscribers {
+            registered.add_query(subscriber);
+        }
+        for subscriber in source_subscribers {
+            registered.add_source(subscriber);
+        }
+        Arc::new(Self {
+            access: Arc::new(RldpAccess::with_options(&options)),
+            answer_cache: RldpAnswerCache::with_options(&options).map(Arc::new),
+            clock: options.clock.clone(),
+            coalescer: if options.coalesce_queries {
+                Some(RldpCoalescer::new())
+            } else {
+                None
+            },
+            incoming: Arc::new(RldpIncoming::with_options(&options)),
+            limits: Arc::new(RldpAnswerLimits::with_options(&options)),
+            load: Arc::new(RldpLoad::with_options(&options)),
+            lookahead: options.send_lookahead,
+            middleware: Arc::new(RldpMiddlewareChain::new(options.middleware.clone())),
+            peers: DashMap::new(),
+            penalties: Arc::new(RldpPenalties::with_options(&options)),
+            pool: Arc::new(RaptorqPool::with_params(
+                options.codec_threads,
+                options.encoder_cache_size,
+                workers.clone(),
+            )),
+            rng: options.rng.clone(),
+            spill_dir: options.spill_dir.clone(),
+            state,
+            state_reader,
+            subscribers: std::sync::RwLock::new(Arc::new(registered)),
+            tasks: std::sync::Mutex::new(Some(tasks)),
+            tasks_done: tokio::sync::Mutex::new(tasks_done),
+            transfers: Arc::new(RldpTransfers::with_options(&options)),
+            transport,
+            workers,
+        })
+    }
+
+    /// Register subscriber, it is called for queries received after registration
+    pub fn add_subscriber(&self, subscriber: Arc<dyn Subscriber>) -> RldpSubscriberHandle {
+        self.update_subscribers(|subscribers| subscribers.add_query(subscriber))
+    }
+
+    /// Register source subscriber, it is called for queries received after registration
+    pub fn add_source_subscriber(
+        &self,
+        subscriber: Arc<dyn RldpSourceSubscriber>,
+    ) -> RldpSubscriberHandle {
+        self.update_subscribers(|subscribers| subscribers.add_source(subscriber))
+    }
+
+    /// Route queries with given TL constructor ID to handler, it is tried before subscribers.
+    /// Fails if the ID is already routed, remove previous handler to replace it
+    pub fn add_route(
+        &self,
+        constructor: u32,
+        handler: Arc<dyn RldpSourceSubscriber>,
+    ) -> Result<RldpSubscriberHandle> {
+        self.update_subscribers(|subscribers| subscribers.add_route(constructor, handler))
+    }
+
+    /// Route queries with TL constructor ID in range to handler, exact routes take precedence
+    pub fn add_route_range(
+        &self,
+        constructors: RangeInclusive<u32>,
+        handler: Arc<dyn RldpSourceSubscriber>,
+    ) -> RldpSubscriberHandle {
+        self.update_subscribers(|subscribers| subscribers.add_range(constructors, handler))
+    }
+
+    /// Unregister subscriber or route handler, queries in progress still may use it
+    pub fn remove_subscriber(&self, handle: &RldpSubscriberHandle) -> bool {
+        self.update_subscribers(|subscribers| subscribers.remove(handle))
+    }
+
+    /// Banned peers with remaining ban time
+    pub fn banned_peers(&self) -> Vec<(Arc<KeyId>, Duration)> {
+        self.penalties.banned(self.clock.now())
+    }
+
+    /// Lift ban of peer and reset its penalty score, false if peer is not banned
+    pub fn unban_peer(&self, peer: &Arc<KeyId>) -> bool {
+        self.penalties.unban(peer)
+    }
+
+    /// Lift all bans and reset penalty scores
+    pub fn clear_bans(&self) {
+        self.penalties.clear()
+    }
+
+    /// Statistics
+    pub fn stats(&self) -> RldpNodeStats {
+        let mut stats = RldpNodeStats {
+            peers_banned: self.banned_peers().len() as u64,
+            ..Default::default()
+        };
+        self.access.fill_stats(&mut stats);
+        self.incoming.fill_stats(&mut stats);
+        self.limits.fill_stats(&mut stats);
+        self.load.fill_stats(&mut stats);
+        self.pool.fill_stats(&mut stats);
+        self.subscribers().fill_stats(&mut stats);
+        self.transfers.fill_stats(&mut stats);
+        if let Some(cache) = &self.answer_cache {
+            cache.fill_stats(&mut stats)
+        }
+        if let Some(coalescer) = &self.coalescer {
+            coalescer.fill_stats(&mut stats)
+        }
+        stats
+    }
+
+    /// Shutdown: stop accepting transfers, cancel queries, let answers in flight
+    /// finish within `drain` timeout, then cancel the rest and await all tasks
+    pub async fn shutdown(&self, drain: Option<Duration>) {
+        self.state.send(RldpNodeState::Draining).ok();
+        if let Ok(mut tasks) = self.tasks.lock() {
+            tasks.take();
+        }
+        let mut tasks_done = self.tasks_done.lock().await;
+        if let Some(drain) = drain {
+            if tokio::time::timeout(drain, tasks_done.recv())
+                .await
+                .is_err()
+            {
+                log::warn!(
+                    target: TARGET,
+                    "RLDP answers are not drained in {} ms, cancelling",
+                    drain.as_millis()
+                )
+            }
+        }
+        self.state.send(RldpNodeState::Stopped).ok();
+        self.workers.stop();
+        while tasks_done.recv().await.is_some() {}
+        self.workers.wait().await;
+        self.transfers.table.clear();
+    }
+
+    /// Send query
+    pub async fn query(
+        &self,
+        data: &[u8],
+        max_answer_size: Option<i64>,
+        peers: &AdnlPeers,
+        roundtrip: Option<u64>,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let query_id: QueryId = self.random_id();
+        if self.middleware.is_empty() {
+            return self
+                .query_coalesced(&query_id, data, max_answer_size, peers, roundtrip)
+                .await;
+        }
+        let mut query = RldpQueryInfo {
+            data: data.to_vec(),
+            deadline: Self::query_deadline(),
+            incoming: false,
+            max_answer_size: max_answer_size.unwrap_or(Self::MAX_ANSWER_SIZE),
+            peers: peers.clone(),
+            query_id,
+        };
+        let (passed, outcome) = self.middleware.before(&mut query).await;
+        // Roundtrip is not measured when query is not sent
+        let (outcome, roundtrip) = match outcome {
+            Some(outcome) => (outcome, roundtrip.unwrap_or_default()),
+            None => {
+                let max_answer_size = Some(query.max_answer_size);
+                let answer = self
+                    .query_coalesced(&query_id, &query.data, max_answer_size, peers, roundtrip)
+                    .await;
+                match answer {
+                    Ok((answer, roundtrip)) => (
+                        answer.map_or(RldpOutcome::Empty, RldpOutcome::Answer),
+                        roundtrip,
+                    ),
+                    Err(e) => (RldpOutcome::Error(e), roundtrip.unwrap_or_default()),
+                }
+            }
+        };
+        match self.middleware.after(passed, &query, outcome).await {
+            RldpOutcome::Answer(answer) => Ok((Some(answer), roundtrip)),
+            RldpOutcome::Source(_) | RldpOutcome::Streamed => {
+                fail!("Unexpected answer source for outgoing RLDP query")
+            }
+            RldpOutcome::Empty => Ok((None, roundtrip)),
+            RldpOutcome::Error(e) => Err(e),
+        }
+    }
+
+    async fn query_coalesced(
+        &self,
+        query_id: &QueryId,
+        data: &[u8],
+        max_answer_size: Option<i64>,
+        peers: &AdnlPeers,
+        roundtrip: Option<u64>,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let exchange = self.query_exchange(query_id, data, max_answer_size, peers, roundtrip);
+        match &self.coalescer {
+            Some(coalescer) => {
+                coalescer
+                    .query(data, max_answer_size, peers, exchange)
+                    .await
+            }
+            None => exchange.await,
+        }
+    }
+
+    async fn query_exchange(
+        &self,
+        query_id: &QueryId,
+        data: &[u8],
+        max_answer_size: Option<i64>,
+        peers: &AdnlPeers,
+        roundtrip: Option<u64>,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let query_id = *query_id;
+        let (answer, roundtrip) = self
+            .query_transfer(&query_id, data, max_answer_size, peers, roundtrip, None)
+            .await?;
+        if let Some(answer) = answer {
+            match deserialize(&answer[..])?.downcast::<RldpMessageBoxed>() {
+                Ok(RldpMessageBoxed::Rldp_Answer(answer)) => {
+                    if answer.query_id.0 != query_id {
+                        fail!("Unknown query ID in RLDP answer")
+                    } else {
+                        log::trace!(
+                            target: TARGET,
+                            "RLDP answer {:02x}{:02x}{:02x}{:02x}...",
+                            answer.data[0],
+                            answer.data[1],
+                            answer.data[2],
+                            answer.data[3]
+                        );
+                        Ok((Some(answer.data.to_vec()), roundtrip))
+                    }
+                }
+                Ok(answer) => fail!("Unexpected answer to RLDP query: {:?}", answer),
+                Err(answer) => fail!("Unexpected answer to RLDP query: {:?}", answer),
+            }
+        } else {
+            Ok((None, roundtrip))
+        }
+    }
+
+    /// Send query, answer bigger than threshold is spilled to temporary file
+    pub async fn query_spilled(
+        self: &Arc<Self>,
+        data: &[u8],
+        max_answer_size: Option<i64>,
+        peers: &AdnlPeers,
+        roundtrip: Option<u64>,
+        spill_threshold: usize,
+    ) -> Result<RldpAnswerData> {
+        let mut stream = self
+            .query_stream(data, max_answer_size, peers, roundtrip)
+            .await?;
+        RldpAnswerData::read_from(&mut stream, spill_threshold, &self.spill_dir).await
+    }
+
+    /// Send query, answer is read as a stream while being received
+    pub async fn query_stream(
+        self: &Arc<Self>,
+        data: &[u8],
+        max_answer_size: Option<i64>,
+        peers: &AdnlPeers,
+        roundtrip: Option<u64>,
+    ) -> Result<RldpAnswerStream> {
+        let mut query = RldpQueryInfo {
+            data: data.to_vec(),
+            deadline: Self::query_deadline(),
+            incoming: false,
+            max_answer_size: max_answer_size.unwrap_or(Self::MAX_ANSWER_SIZE),
+            peers: peers.clone(),
+            query_id: self.random_id(),
+        };
+        let (passed, outcome) = self.middleware.before(&mut query).await;
+        let (sender, reader) = mpsc::channel(RldpAnswerStream::PARTS);
+        let stream = RldpAnswerStream::with_query(&query.query_id, reader)?;
+        if let Some(outcome) = outcome {
+            let outcome = self.middleware.after(passed, &query, outcome).await;
+            let chunk = RldpAnswerStream::answer_chunk(&query.query_id, outcome);
+            sender.try_send(chunk).ok();
+            return Ok(stream);
+        }
+        let errors = sender.clone();
+        let node = self.clone();
+        let mut state = self.state_reader.clone();
+        let spawned = self.spawn(async move {
+            let outcome = match node
+                .query_transfer(
+                    &query.query_id,
+                    &query.data,
+                    Some(query.max_answer_size),
+                    &query.peers,
+                    roundtrip,
+                    Some(sender),
+                )
+                .await
+            {
+                Ok((Some(_), _)) => RldpOutcome::Streamed,
+                Ok((None, _)) => RldpOutcome::Empty,
+                Err(e) => RldpOutcome::Error(e),
+            };
+            let streamed = matches!(outcome, RldpOutcome::Streamed);
+            let outcome = tokio::select! {
+                outcome = node.middleware.after(passed, &query, outcome) => outcome,
+                _ = Self::cancelled(&mut state, RldpNodeState::Stopped) => return,
+            };
+            let chunk = match outcome {
+                RldpOutcome::Streamed => return,
+                RldpOutcome::Answer(_) if streamed => Err(failure::format_err!(
+                    "Streamed answer to RLDP query cannot be replaced"
+                )),
+                outcome => RldpAnswerStream::answer_chunk(&query.query_id, outcome),
+            };
+            // Reader may stop reading, so shutdown must not wait for it
+            tokio::select! {
+                _ = errors.send(chunk) => (),
+                _ = Self::cancelled(&mut state, RldpNodeState::Draining) => (),
+            }
+        });
+        if !spawned {
+            fail!("RLDP node is shut down")
+        }
+        Ok(stream)
+    }
+
+    fn answer_transfer(
+        &self,
+        transfer_id: &TransferId,
+        peers: &AdnlPeers,
+        incoming: RldpIncomingGuard,
+    ) -> Result<Option<Arc<RldpTransferQueue>>> {
+        let (queue, queue_reader) = self.transfers.queue();
+        if !self.transfers.try_insert(transfer_id, &queue) {
+            return Ok(None);
+        }
+        self.start_janitor();
+
+        // Answers in flight are drained on shutdown
+        let mut context = RldpContext {
+            buf: Vec::new(),
+            cancel: RldpNodeState::Stopped,
+            clock: self.clock.clone(),
+            limits: Some(self.limits.clone()),
+            lookahead: self.lookahead,
+            peers: peers.clone(),
+            penalties: self.penalties.clone(),
+            pool: self.pool.clone(),
+            queue_reader,
+            state: self.state_reader.clone(),
+            transport: self.transport.clone(),
+        };
+        let mut recv_transfer = RldpRecvTransfer::new(*transfer_id, false);
+        let deadline = context.clock.now() + Duration::from_millis(RldpTimer::TIMEOUT_MAX);
+        recv_transfer.set_deadline(deadline);
+        let server = RldpQueryServer {
+            access: self.access.clone(),
+            answer_cache: self.answer_cache.clone(),
+            clock: self.clock.clone(),
+            load: self.load.clone(),
+            middleware: self.middleware.clone(),
+            penalties: self.penalties.clone(),
+            subscribers: self.subscribers(),
+        };
+
+        let spawned = self.spawn({
+            let transfers = self.transfers.clone();
+            let transfer_id = *transfer_id;
+
+            async move {
+                let _incoming = incoming;
+                Self::receive_loop(&mut context, &mut recv_transfer).await;
+                transfers.done(&transfer_id);
+
+                match recv_transfer.status() {
+                    RldpTransferStatus::Done => Self::answer_transfer_loop(
+                        &mut context,
+                        recv_transfer.take_data(),
+                        &transfer_id,
+                        &server,
+                        &transfers,
+                    )
+                    .await
+                    .unwrap_or_else(|e| {
+                        log::warn!(
+                            target: TARGET,
+                            "ERROR: {}, transfer {}",
+                            e,
+                            base64::encode(&transfer_id)
+                        )
+                    }),
+                    RldpTransferStatus::TimedOut => log::warn!(
+                        target: TARGET,
+                        "Timeout on query in RLDP transfer {} from {}",
+                        base64::encode(&transfer_id),
+                        context.peers.other()
+                    ),
+                    RldpTransferStatus::Active | RldpTransferStatus::Failed => (),
+                }
+            }
+        });
+        if !spawned {
+            self.transfers.table.remove(transfer_id);
+            fail!("RLDP node is shut down")
+        }
+
+        Ok(Some(queue))
+    }
+
+    async fn answer_transfer_loop(
+        context: &mut RldpContext,
+        data: Vec<u8>,
+        transfer_id: &TransferId,
+        server: &RldpQueryServer,
+        transfers: &RldpTransfers,
+    ) -> Result<()> {
+        let received = context.clock.now();
+        let query = match server.accept(&data, &context.peers)? {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+        let deadline = RldpQueryServer::deadline(&query, received);
+        let mut in_flight = server.load.start();
+
+        // Processing is given up on shutdown, spawned subscriber is aborted with it
+        let (mut state, cancel) = (context.state.clone(), context.cancel);
+        let outcome = tokio::select! {
+            outcome = server.serve(query.clone(), &context.peers, transfer_id, deadline) => {
+                outcome
+            }
+            _ = Self::cancelled(&mut state, cancel) => fail!("RLDP node is shut down"),
+        };
+        let mut source = match RldpQueryServer::answer_source(&query, outcome)? {
+            Some(source) => source,
+            None => return Ok(()),
+        };
+        in_flight.add_bytes(source.size() as u64);
+        let mut send_transfer_id = *transfer_id;
+        for byte in &mut send_transfer_id {
+            *byte ^= 0xFF
+        }
+        log::trace!(
+            target: TARGET,
+            "RLDP answer to be sent in transfer {}/{} to {}",
+            base64::encode(transfer_id),
+            base64::encode(&send_transfer_id),
+            context.peers.other()
+        );
+
+        let (queue, queue_reader) = transfers.queue();
+        context.queue_reader = queue_reader;
+        transfers.insert(&send_transfer_id, &queue);
+        drop(queue);
+        let mut send_transfer =
+            RldpSendTransfer::new(send_transfer_id, source.size(), None, context.clock.now());
+        send_transfer.set_lookahead(context.lookahead);
+
+        let sent = Self::send_loop(context, &mut send_transfer, source.as_mut()).await;
+        transfers.done(&send_transfer_id);
+        if sent? {
+            log::trace!(
+                target: TARGET,
+                "RLDP answer sent in transfer {} to {}",
+                base64::encode(transfer_id),
+                context.peers.other()
+            )
+        } else {
+            log::warn!(
+                target: TARGET,
+                "Timeout on answer in RLDP transfer {} to {}",
+                base64::encode(transfer_id),
+                context.peers.other()
+            )
+        }
+
+        Ok(())
+    }
+
+    fn subscribers(&self) -> Arc<RldpSubscribers> {
+        match self.subscribers.read() {
+            Ok(subscribers) => subscribers.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn update_subscribers<T>(&self, update: impl FnOnce(&mut RldpSubscribers) -> T) -> T {
+        let mut subscribers = match self.subscribers.write() {
+            Ok(subscribers) => subscribers,
+            Err(e) => e.into_inner(),
+        };
+        let mut updated = subscribers.as_ref().clone();
+        let ret = update(&mut updated);
+        *subscribers = Arc::new(updated);
+        ret
+    }
+
+    fn query_deadline() -> i32 {
+        now() + RldpTimer::TIMEOUT_MAX as i32 / 1000
+    }
+
+    fn random_id(&self) -> [u8; 32] {
+        let mut id = [0u8; 32];
+        self.rng.fill(&mut id);
+        id
+    }
+
+    async fn query_transfer(
+        &self,
+        query_id: &QueryId,
+        data: &[u8],
+        max_answer_size: Option<i64>,
+        peers: &AdnlPeers,
+        roundtrip: Option<u64>,
+        stream: Option<mpsc::Sender<Result<Vec<u8>>>>,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        use dashmap::mapref::entry::Entry;
+
+        if !self.is_running() {
+            fail!("RLDP node is shut down")
+        }
+        if self.penalties.is_banned(peers.other(), self.clock.now()) {
+            fail!("RLDP peer {} is banned", peers.other())
+        }
+        let max_answer_size = max_answer_size.unwrap_or(Self::MAX_ANSWER_SIZE);
+        let mut query = serialize(
+            &RldpQuery {
+                query_id: ton::int256(*query_id),
+                max_answer_size,
+                timeout: Self::query_deadline(),
+                data: ton::bytes(Vec::new()),
+            }
+            .into_boxed(),
+        )?;
+        fill_tl_bytes(&mut query, data)?;
+        let mut data = RldpBytes::from(query);
+
+        let peer = match self.peers.entry(peers.other().clone()) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => entry
+                .insert(Arc::new(RldpPeer {
+                    queries: AtomicU32::new(0),
+                    queue: lockfree::queue::Queue::new(),
+                }))
+                .value()
+                .clone(),
+        };
+
+        let queries = peer.queries.fetch_add(1, Ordering::Acquire);
+        if queries >= Self::MAX_QUERIES {
+            let ping = Arc::new(tokio::sync::Barrier::new(2));
+            peer.queue.push(ping.clone());
+            ping.wait().await;
+        }
+
+        let total = data.len();
+        let send_transfer_id: TransferId = self.random_id();
+        let mut recv_transfer_id = send_transfer_id;
+        for byte in &mut recv_transfer_id {
+            *byte ^= 0xFF
+        }
+        // Both directions of query are driven by single loop
+        let (queue, queue_reader) = self.transfers.queue();
+        self.transfers.insert(&send_transfer_id, &queue);
+        self.transfers.insert(&recv_transfer_id, &queue);
+        drop(queue);
+        self.start_janitor();
+        let mut context = RldpContext {
+            buf: Vec::new(),
+            cancel: RldpNodeState::Draining,
+            clock: self.clock.clone(),
+            limits: None,
+            lookahead: self.lookahead,
+            peers: peers.clone(),
+            penalties: self.penalties.clone(),
+            pool: self.pool.clone(),
+            queue_reader,
+            state: self.state_reader.clone(),
+            transport: self.transport.clone(),
+        };
+        let mut send_transfer =
+            RldpSendTransfer::new(send_transfer_id, total, roundtrip, context.clock.now());
+        send_transfer.set_lookahead(context.lookahead);
+        let mut recv_transfer = RldpRecvTransfer::new(recv_transfer_id, stream.is_some());
+        recv_transfer
+            .set_max_size(std::cmp::max(max_answer_size, 0) as usize + Self::ANSWER_OVERHEAD);
+        log::trace!(
+            target: TARGET,
+            "transfer id {}/{}, total to send {}",
+            base64::encode(&send_transfer_id),
+            base64::encode(&recv_transfer_id),
+            total
+        );
+        let res = self
+            .query_transfer_loop(
+                &mut context,
+                &mut send_transfer,
+                &mut data,
+                &mut recv_transfer,
+                stream,
+            )
+            .await;
+        // Send side may be done already, marking again only extends its linger
+        self.transfers.done(&send_transfer_id);
+        self.transfers.done(&recv_transfer_id);
+
+        let queries = peer.queries.fetch_sub(1, Ordering::Acquire);
+        if queries > Self::MAX_QUERIES {
+            loop {
+                if let Some(pong) = peer.queue.pop() {
+                    pong.wait().await;
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        }
+        res
+    }
+
+    async fn query_transfer_loop(
+        &self,
+        context: &mut RldpContext,
+        send_transfer: &mut RldpSendTransfer,
+        source: &mut dyn RldpSendSource,
+        recv_transfer: &mut RldpRecvTransfer,
+        stream: Option<mpsc::Sender<Result<Vec<u8>>>>,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let transfer_id = *send_transfer.transfer_id();
+        let mut waiting = false;
+        loop {
+            if !waiting {
+                Self::send_packets(context, send_transfer, source).await?;
+                match send_transfer.status() {
+                    RldpTransferStatus::Active => (),
+                    RldpTransferStatus::Done => {
+                        self.transfers.done(&transfer_id);
+                        log::trace!(
+                            target: TARGET,
+                            "RLDP query sent in transfer {} to {}, waiting for answer",
+                            base64::encode(&transfer_id),
+                            context.peers.other()
+                        );
+                        recv_transfer.start_waiting(send_transfer.roundtrip(), context.clock.now());
+                        waiting = true;
+                    }
+                    RldpTransferStatus::TimedOut => {
+                        log::warn!(
+                            target: TARGET,
+                            "Timeout ({} ms) on query in RLDP transfer {} to {}",
+                            RldpTimer::calc_timeout(Some(send_transfer.roundtrip())),
+                            base64::encode(&transfer_id),
+                            context.peers.other()
+                        );
+                        return Ok((None, send_transfer.roundtrip()));
+                    }
+                    RldpTransferStatus::Failed => fail!(
+                        "RLDP transfer {} to {} failed",
+                        base64::encode(&transfer_id),
+                        context.peers.other()
+                    ),
+                }
+            }
+            let roundtrip = recv_transfer
+                .roundtrip()
+                .unwrap_or_else(|| send_transfer.roundtrip());
+            match recv_transfer.status() {
+                RldpTransferStatus::Active => (),
+                RldpTransferStatus::Done => {
+                    log::trace!(
+                        target: TARGET,
+                        "Got reply for transfer {} from {}",
+                        base64::encode(&transfer_id),
+                        context.peers.other()
+                    );
+                    return Ok((Some(recv_transfer.take_data()), roundtrip));
+                }
+                RldpTransferStatus::TimedOut => {
+                    log::warn!(
+                        target: TARGET,
+                        "No activity for transfer {} to {} in {} ms, aborting",
+                        base64::encode(&transfer_id),
+                        context.peers.other(),
+                        RldpTimer::calc_timeout(Some(roundtrip))
+                    );
+                    return Ok((None, roundtrip));
+                }
+                RldpTransferStatus::Failed => fail!(
+                    "Answer in RLDP transfer {} from {} is rejected",
+                    base64::encode(&transfer_id),
+                    context.peers.other()
+                ),
+            }
+            let deadline = if waiting {
+                recv_transfer.poll_timeout()
+            } else {
+                send_transfer.poll_timeout()
+            };
+            match Self::wait_packet(context, deadline).await? {
+                Some(RldpMessagePartBoxed::Rldp_MessagePart(message)) => {
+                    send_transfer.handle_reply(context.clock.now());
+                    if let Err(e) = Self::receive_packet(context, recv_transfer, *message).await {
+                        log::warn!("RLDP error: {}", e)
+                    }
+                    if let Some(stream) = &stream {
+                        while let Some(part) = recv_transfer.poll_data() {
+                            let sent = tokio::select! {
+                                sent = stream.send(Ok(part)) => sent,
+                                _ = Self::cancelled(&mut context.state, context.cancel) => {
+                                    fail!("RLDP node is shut down")
+                                }
+                            };
+                            if sent.is_err() {
+                                log::trace!(
+                                    target: TARGET,
+                                    "transfer id {}, answer stream closed",
+                                    base64::encode(recv_transfer.transfer_id())
+                                );
+                                return Ok((Some(Vec::new()), roundtrip));
+                            }
+                        }
+                    }
+                }
+                Some(message) => send_transfer.handle_packet(&message, context.clock.now()),
+                None if waiting => recv_transfer.handle_timer(context.clock.now()),
+                None => send_transfer.handle_timer(context.clock.now()),
+            }
+        }
+    }
+
+    async fn receive_loop(context: &mut RldpContext, recv_transfer: &mut RldpRecvTransfer) {
+        loop {
+            let begin = recv_transfer.received() == 0;
+            let deadline = recv_transfer.poll_timeout();
+            match Self::wait_packet(context, deadline).await {
+                Ok(Some(RldpMessagePartBoxed::Rldp_MessagePart(message))) => {
+                    if let Err(e) = Self::receive_packet(context, recv_transfer, *message).await {
+                        log::warn!("RLDP error: {}", e)
+                    }
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => recv_transfer.handle_timer(context.clock.now()),
+                Err(e) => {
+                    log::warn!("RLDP error: {}", e);
+                    break;
+                }
+            }
+            if begin && (recv_transfer.received() > 0) {
+                log::trace!(
+                    target: TARGET,
+                    "transfer id {}, received first {}, total to receive {:?}",
+                    base64::encode(recv_transfer.transfer_id()),
+                    recv_transfer.received(),
+                    recv_transfer.total_size()
+                );
+            }
+            match recv_transfer.status() {
+                RldpTransferStatus::Active => (),
+                RldpTransferStatus::Done => {
+                    log::trace!(
+                        target: TARGET,
+                        "transfer id {}, receive completed ({})",
+                        base64::encode(recv_transfer.transfer_id()),
+                        recv_transfer.received(),
+                    );
+                    break;
+                }
+                RldpTransferStatus::TimedOut | RldpTransferStatus::Failed => break,
+            }
+        }
+        // Graceful close
+        context.queue_reader.close();
+        while context.queue_reader.recv().await.is_some() {}
+    }
+
+    async fn receive_packet(
+        context: &mut RldpContext,
+        recv_transfer: &mut RldpRecvTransfer,
+        message: RldpMessagePart,
+    ) -> Result<()> {
+        let penalize = |e: failure::Error| {
+            context.penalties.penalize(
+                context.peers.other(),
+                RldpPenalties::PACKET,
+                &e.to_string(),
+                context.clock.now(),
+            );
+            e
+        };
+        let job = recv_transfer
+            .handle_packet(message, context.clock.now())
+            .map_err(penalize)?;
+        if let Some(job) = job {
+            let job = context.pool.decode(job).await?;
+            recv_transfer
+                .complete_job(job, context.clock.now())
+                .map_err(penalize)?;
+        }
+        if recv_transfer.poll_outgoing(&mut context.buf)? {
+            context
+                .transport
+                .send_custom(&context.buf, &context.peers)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn send_loop(
+        context: &mut RldpContext,
+        send_transfer: &mut RldpSendTransfer,
+        source: &mut dyn RldpSendSource,
+    ) -> Result<bool> {
+        loop {
+            Self::send_packets(context, send_transfer, source).await?;
+            match send_transfer.status() {
+                RldpTransferStatus::Active => (),
+                RldpTransferStatus::Done => return Ok(true),
+                RldpTransferStatus::TimedOut | RldpTransferStatus::Failed => return Ok(false),
+            }
+            let deadline = send_transfer.poll_timeout();
+            match Self::wait_packet(context, deadline).await? {
+                Some(message) => send_transfer.handle_packet(&message, context.clock.now()),
+                None => send_transfer.handle_timer(context.clock.now()),
+            }
+        }
+    }
+
+    async fn send_packets(
+        context: &mut RldpContext,
+        send_transfer: &mut RldpSendTransfer,
+        source: &mut dyn RldpSendSource,
+    ) -> Result<()> {
+        while let Some(request) = send_transfer.poll_part() {
+            // Source may stall, so it is not waited for on shutdown
+            let pool = context.pool.clone();
+            let encode = async {
+                let data = source.read(request.offset, request.len).await?;
+                pool.encoder(data).await
+            };
+            let encoder = tokio::select! {
+                encoder = encode => encoder?,
+                _ = Self::cancelled(&mut context.state, context.cancel) => {
+                    fail!("RLDP node is shut down")
+                }
+            };
+            send_transfer.provide_part(request.part, encoder, context.clock.now());
+        }
+        while send_transfer.poll_outgoing(&mut context.buf)? {
+            if let Some(limits) = &context.limits {
+                let len = context.buf.len() as u64;
+                let delay = limits.reserve_bytes(context.peers.other(), len, context.clock.now());
+                if delay > Duration::from_millis(0) {
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => (),
+                        _ = Self::cancelled(&mut context.state, context.cancel) => {
+                            fail!("RLDP node is shut down")
+                        }
+                    }
+                }
+            }
+            context
+                .transport
+                .send_custom(&context.buf, &context.peers)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Next packet of transfer, None if deadline is reached first
+    async fn wait_packet(
+        context: &mut RldpContext,
+        deadline: Option<Instant>,
+    ) -> Result<Option<RldpMessagePartBoxed>> {
+        let RldpContext {
+            cancel,
+            clock,
+            queue_reader,
+            state,
+            ..
+        } = context;
+        let wait = async {
+            match deadline {
+                Some(deadline) => {
+                    let wait = deadline.saturating_duration_since(clock.now());
+                    tokio::time::timeout(wait, queue_reader.recv()).await.ok()
+                }
+                None => Some(queue_reader.recv().await),
+            }
+        };
+        let packet = tokio::select! {
+            packet = wait => match packet {
+                Some(packet) => packet,
+                None => return Ok(None),
+            },
+            _ = Self::cancelled(state, *cancel) => fail!("RLDP node is shut down"),
+        };
+        match packet {
+            Some(packet) => Ok(Some(packet)),
+            None => fail!("RLDP transfer queue is closed"),
+        }
+    }
+
+    fn penalize_packet(&self, peers: &AdnlPeers, reason: &str) {
+        let now = self.clock.now();
+        self.penalties
+            .penalize(peers.other(), RldpPenalties::PACKET, reason, now)
+    }
+
+    fn is_running(&self) -> bool {
+        *self.state_reader.borrow() == RldpNodeState::Running
+    }
+
+    async fn cancelled(state: &mut watch::Receiver<RldpNodeState>, level: RldpNodeState) {
+        while *state.borrow() < level {
+            if state.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // Single task expires entries of transfer table, started with first transfer
+    fn start_janitor(&self) {
+        if !self.transfers.start_janitor() {
+            return;
+        }
+        let transfers = self.transfers.clone();
+        let mut state = self.state_reader.clone();
+        self.spawn(async move {
+            let tick = Duration::from_millis(RldpTransfers::TICK);
+            while tokio::time::timeout(tick, Self::cancelled(&mut state, RldpNodeState::Draining))
+                .await
+                .is_err()
+            {
+                transfers.expire()
+            }
+        });
+    }
+
+    // Spawn task awaited on shutdown, false if node is shut down
+    fn spawn(&self, task: impl std::future::Future<Output = ()> + Send + 'static) -> bool {
+        let guard = match self.tasks.lock() {
+            Ok(tasks) => tasks.clone(),
+            Err(_) => None,
+        };
+        match guard {
+            Some(guard) => {
+                tokio::spawn(async move {
+                    task.await;
+                    drop(guard)
+                });
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Subscriber for RldpNode {
+    async fn try_consume_custom(&self, data: &[u8], peers: &AdnlPeers) -> Result<bool> {
+        // Malformed packet is left to other subscribers, but it counts against peer
+        let msg = match deserialize(data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                self.penalize_packet(peers, &e.to_string());
+                return Ok(false);
+            }
+        };
+
+        let msg = match msg.downcast::<RldpMessagePartBoxed>() {
+            Ok(msg) => msg,
+            Err(msg) => {
+                self.penalize_packet(peers, &format!("Unexpected RLDP message: {:?}", msg));
+                return Ok(false);
+            }
+        };
+
+        // Packets of banned peers are consumed and dropped
+        if self.penalties.is_banned(peers.other(), self.clock.now()) {
+            return Ok(true);
+        }
+        let transfer_id = *get256(msg.transfer_id());
+        loop {
+            let transfer = match self.transfers.table.get(&transfer_id) {
+                Some(transfer) => transfer,
+                None => {
+                    // No new transfers on shutdown, over limits, on overload or from denied peers
+                    let incoming = match (&msg, self.is_running()) {
+                        (RldpMessagePartBoxed::Rldp_MessagePart(_), true)
+                            if self.access.authorize_peer(peers.other())
+                                && self.load.admit(peers.other()) =>
+                        {
+                            // Query token is spent only on transfer to be started
+                            self.incoming
+                                .acquire(peers.other())
+                                .filter(|_| self.limits.admit(peers.other(), self.clock.now()))
+                        }
+                        _ => None,
+                    };
+                    if let Some(incoming) = incoming {
+                        if let Some(queue) = self.answer_transfer(&transfer_id, peers, incoming)? {
+                            self.transfers.push(&queue, msg);
+                        } else {
+                            continue;
+                        }
+                    }
+                    break;
+                }
+            };
+            if let RldpTransfer::Active(queue) = transfer.value() {
+                self.transfers.push(queue, msg);
+                break;
+            }
+            drop(transfer);
+            if let RldpMessagePartBoxed::Rldp_MessagePart(msg) = msg {
+                let reply = RldpConfirm {
+                    transfer_id: msg.transfer_id,
+                    part: msg.part,
+                    seqno: msg.seqno,
+                }
+                .into_boxed();
+                let reply = serialize(&reply)?;
+                self.transport.send_custom(&reply[..], peers).await?;
+                let reply = RldpComplete {
+                    transfer_id: msg.transfer_id,
+                    part: msg.part,
+                }
+                .into_boxed();
+                let reply = serialize(&reply)?;
+                self.transport.send_custom(&reply[..], peers).await?;
+                log::info!(
+                    target: TARGET,
+                    "Receive update on closed RLDP transfer {}, part {}, seqno {}",
+                    base64::encode(&transfer_id),
+                    msg.part,
+                    msg.seqno
+                );
+            }
+            break;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU64};
+
+    use super::*;
+    use crate::testing::*;
+    use crate::{RldpAuthorizer, RldpDecision, RldpMiddleware};
+
+    #[tokio::test]
+    async fn test_transfers_stat_back_to_zero() {
+        let clock = TestClock::new();
+        let transport = Arc::new(TestTransport::default());
+        let options = RldpNodeOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let node = RldpNode::with_transport(transport.clone(), Vec::new(), Vec::new(), options);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let query = tokio::spawn({
+            let node = node.clone();
+            async move { node.query(b"query", None, &peers, None).await }
+        });
+        while transport.sent.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await
+        }
+        assert_eq!(node.stats().transfers, 2);
+        // Cancelled query leaves its transfers to janitor
+        query.abort();
+        assert!(query.await.unwrap_err().is_cancelled());
+        clock.advance(RldpTransfers::STUCK + RldpTransfers::TICK);
+        let wait = Duration::from_secs(5);
+        tokio::time::timeout(wait, async {
+            while node.stats().transfers_expired < 2 {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        // Expired transfers linger as done ones
+        assert_eq!(node.stats().transfers, 2);
+        clock.advance(RldpTransfers::LINGER + RldpTransfers::TICK);
+        tokio::time::timeout(wait, async {
+            while node.stats().transfers > 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        node.shutdown(None).await;
+    }
+
+    // First packet of query sent by node with seeded RNG and frozen clock
+    async fn seeded_query_packet(seed: u64) -> RldpMessagePart {
+        use rand::SeedableRng;
+
+        let transport = Arc::new(TestTransport::default());
+        let options = RldpNodeOptions {
+            clock: TestClock::new(),
+            rng: Arc::new(std::sync::Mutex::new(rand::rngs::StdRng::seed_from_u64(
+                seed,
+            ))),
+            ..Default::default()
+        };
+        let node = RldpNode::with_transport(transport.clone(), Vec::new(), Vec::new(), options);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let query = tokio::spawn({
+            let node = node.clone();
+            async move { node.query(b"query", None, &peers, None).await }
+        });
+        while transport.sent.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await
+        }
+        node.shutdown(None).await;
+        assert!(query.await.unwrap().is_err());
+        let sent = transport.sent.lock().unwrap();
+        message_part(&sent[0])
+    }
+
+    #[tokio::test]
+    async fn test_seeded_transfer_ids() {
+        let first = seeded_query_packet(1).await;
+        let second = seeded_query_packet(1).await;
+        assert_eq!(first.transfer_id, second.transfer_id);
+        assert_eq!((first.part, first.seqno), (second.part, second.seqno));
+        let other = seeded_query_packet(2).await;
+        assert_ne!(first.transfer_id, other.transfer_id);
+    }
+
+    // Transfer IDs of message parts sent so far
+    fn sent_transfers(transport: &TestTransport) -> std::collections::HashSet<TransferId> {
+        let sent = transport.sent.lock().unwrap();
+        sent.iter()
+            .map(|packet| message_part(packet).transfer_id.0)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_leader_cancelled() {
+        let transport = Arc::new(TestTransport::default());
+        let options = RldpNodeOptions {
+            coalesce_queries: true,
+            ..Default::default()
+        };
+        let node = RldpNode::with_transport(transport.clone(), Vec::new(), Vec::new(), options);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let query = |node: Arc<RldpNode>, peers: AdnlPeers| {
+            tokio::spawn(async move { node.query(b"query", None, &peers, None).await })
+        };
+        let leader = query(node.clone(), peers.clone());
+        while sent_transfers(&transport).is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await
+        }
+        let follower = query(node.clone(), peers);
+        while node.stats().queries_coalesced == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await
+        }
+        // Follower sends query on its own instead of failing
+        leader.abort();
+        while sent_transfers(&transport).len() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await
+        }
+        node.shutdown(None).await;
+        let error = follower.await.unwrap().unwrap_err();
+        assert!(error.to_string().contains("shut down"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_refused_transfer_keeps_query_token() {
+        let options = RldpNodeOptions {
+            queries_per_sec: 2,
+            max_incoming_transfers: 1,
+            ..Default::default()
+        };
+        let transport = Arc::new(TestTransport::default());
+        let node = RldpNode::with_transport(transport, Vec::new(), Vec::new(), options);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        // First transfer is not complete, so it keeps its slot
+        let packets = query_packets(test_query(&test_data(10_000)), Instant::now());
+        assert!(node.try_consume_custom(&packets[0], &peers).await.unwrap());
+        for packet in &packets[1..6] {
+            let mut msg = message_part(packet);
+            msg.transfer_id = ton::int256([8; 32]);
+            let packet = serialize(&msg.into_boxed()).unwrap();
+            assert!(node.try_consume_custom(&packet, &peers).await.unwrap());
+        }
+        let stats = node.stats();
+        assert_eq!(stats.incoming_transfers_refused, 5);
+        assert_eq!(stats.queries_rejected, 0);
+        // Only admitted transfer took its token
+        let now = node.clock.now();
+        assert!(node.limits.admit(peers.other(), now));
+        assert!(!node.limits.admit(peers.other(), now));
+        node.shutdown(None).await;
+    }
+
+    #[tokio::test]
+    async fn test_malformed_packets_penalized() {
+        let options = RldpNodeOptions {
+            ban_threshold: 2,
+            ..Default::default()
+        };
+        let transport = Arc::new(TestTransport::default());
+        let node = RldpNode::with_transport(transport, Vec::new(), Vec::new(), options);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        // Garbage and other TL messages are not consumed
+        assert!(!node.try_consume_custom(b"garbage", &peers).await.unwrap());
+        assert!(node.banned_peers().is_empty());
+        let query = serialize(&test_query(b"query").into_boxed()).unwrap();
+        assert!(!node.try_consume_custom(&query, &peers).await.unwrap());
+        assert_eq!(node.banned_peers().len(), 1);
+        // Valid packets of banned peer are dropped
+        let packets = query_packets(test_query(b"query"), Instant::now());
+        assert!(node.try_consume_custom(&packets[0], &peers).await.unwrap());
+        assert_eq!(node.stats().transfers, 0);
+        node.shutdown(None).await;
+    }
+
+    // Denies queries with given data
+    #[derive(Debug)]
+    struct DataAuthorizer(Vec<u8>);
+
+    impl RldpAuthorizer for DataAuthorizer {
+        fn authorize(&self, _peer: &KeyId, query: &RldpQuery) -> RldpDecision {
+            if query.data.0 == self.0 {
+                RldpDecision::Deny
+            } else {
+                RldpDecision::Allow
+            }
+        }
+    }
+
+    fn answer_packets(transport: &TestTransport) -> usize {
+        let sent = transport.sent.lock().unwrap();
+        sent.iter()
+            .filter(|packet| {
+                matches!(
+                    parse_packet(packet),
+                    RldpMessagePartBoxed::Rldp_MessagePart(_)
+                )
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_denied_peer_starts_no_transfer() {
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let options = RldpNodeOptions {
+            denied_peers: vec![peers.other().clone()],
+            ..Default::default()
+        };
+        let transport = Arc::new(TestTransport::default());
+        let subscribers: Vec<Arc<dyn RldpSourceSubscriber>> =
+            vec![Arc::new(DataSubscriber(b"answer".to_vec()))];
+        let node = RldpNode::with_transport(transport.clone(), Vec::new(), subscribers, options);
+        let packets = query_packets(test_query(b"query"), Instant::now());
+        // Packet is consumed, but neither transfer nor reply follows
+        assert!(node.try_consume_custom(&packets[0], &peers).await.unwrap());
+        let stats = node.stats();
+        assert_eq!((stats.transfers, stats.incoming_transfers), (0, 0));
+        assert_eq!(stats.queries_denied, 1);
+        assert!(transport.sent.lock().unwrap().is_empty());
+        node.shutdown(None).await;
+    }
+
+    #[tokio::test]
+    async fn test_authorizer_deny() {
+        let options = RldpNodeOptions {
+            authorizer: Some(Arc::new(DataAuthorizer(b"denied".to_vec()))),
+            ..Default::default()
+        };
+        let transport = Arc::new(TestTransport::default());
+        let subscribers: Vec<Arc<dyn RldpSourceSubscriber>> =
+            vec![Arc::new(DataSubscriber(b"answer".to_vec()))];
+        let node = RldpNode::with_transport(transport.clone(), Vec::new(), subscribers, options);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        for packet in query_packets(test_query(b"denied"), Instant::now()) {
+            node.try_consume_custom(&packet, &peers).await.unwrap();
+        }
+        // Query is dropped once received, before subscribers
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node.stats().queries_denied == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(answer_packets(&transport), 0);
+        // Allowed query of the same peer is answered
+        let mut query = test_query(b"allowed");
+        query.query_id = ton::int256([2; 32]);
+        for packet in query_packets(query, Instant::now()) {
+            let mut msg = message_part(&packet);
+            msg.transfer_id = ton::int256([8; 32]);
+            let packet = serialize(&msg.into_boxed()).unwrap();
+            node.try_consume_custom(&packet, &peers).await.unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while answer_packets(&transport) == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(node.stats().queries_denied, 1);
+        node.shutdown(None).await;
+    }
+
+    // Sets flag on drop
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Relaxed)
+        }
+    }
+
+    // Takes query and never answers
+    struct HungSubscriber {
+        called: Arc<AtomicBool>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl RldpSourceSubscriber for HungSubscriber {
+        async fn try_answer(
+            &self,
+            _query: &RldpQuery,
+            _peers: &AdnlPeers,
+        ) -> Result<Option<Box<dyn RldpSendSource>>> {
+            let _flag = DropFlag(self.dropped.clone());
+            self.called.store(true, Ordering::Relaxed);
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_with_hung_subscriber() {
+        let called = Arc::new(AtomicBool::new(false));
+        let dropped = Arc::new(AtomicBool::new(false));
+        let subscribers: Vec<Arc<dyn RldpSourceSubscriber>> = vec![Arc::new(HungSubscriber {
+            called: called.clone(),
+            dropped: dropped.clone(),
+        })];
+        let options = RldpNodeOptions {
+            subscriber_timeout_ms: 0,
+            ..Default::default()
+        };
+        let transport = Arc::new(TestTransport::default());
+        let node = RldpNode::with_transport(transport, Vec::new(), subscribers, options);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        for packet in query_packets(test_query(b"query"), Instant::now()) {
+            assert!(node.try_consume_custom(&packet, &peers).await.unwrap())
+        }
+        while !called.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(1)).await
+        }
+        let wait = Duration::from_secs(5);
+        tokio::time::timeout(wait, node.shutdown(None))
+            .await
+            .unwrap();
+        // Subscriber task is aborted, not left behind
+        assert!(dropped.load(Ordering::Relaxed));
+    }
+
+    // Panics on any query
+    struct PanicSubscriber;
+
+    #[async_trait::async_trait]
+    impl RldpSourceSubscriber for PanicSubscriber {
+        async fn try_answer(
+            &self,
+            _query: &RldpQuery,
+            _peers: &AdnlPeers,
+        ) -> Result<Option<Box<dyn RldpSendSource>>> {
+            panic!("Test subscriber panic")
+        }
+    }
+
+    // Delivers query in transfer with given ID
+    async fn receive_query(node: &RldpNode, peers: &AdnlPeers, data: &[u8], transfer_id: u8) {
+        for packet in query_packets(test_query(data), Instant::now()) {
+            let mut msg = message_part(&packet);
+            msg.transfer_id = ton::int256([transfer_id; 32]);
+            let packet = serialize(&msg.into_boxed()).unwrap();
+            assert!(node.try_consume_custom(&packet, peers).await.unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_subscribers_accounted() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let options = RldpNodeOptions {
+            subscriber_timeout_ms: 50,
+            ..Default::default()
+        };
+        let transport = Arc::new(TestTransport::default());
+        let subscribers: Vec<Arc<dyn RldpSourceSubscriber>> =
+            vec![Arc::new(DataSubscriber(b"answer".to_vec()))];
+        let node = RldpNode::with_transport(transport.clone(), Vec::new(), subscribers, options);
+        let hung = Arc::new(HungSubscriber {
+            called: Arc::new(AtomicBool::new(false)),
+            dropped: dropped.clone(),
+        });
+        node.add_route(1, hung).unwrap();
+        node.add_route(2, Arc::new(PanicSubscriber)).unwrap();
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        receive_query(&node, &peers, &[1, 0, 0, 0], 1).await;
+        receive_query(&node, &peers, &[2, 0, 0, 0], 2).await;
+        let wait = Duration::from_secs(5);
+        tokio::time::timeout(wait, async {
+            loop {
+                let stats = node.stats();
+                let failed = |constructor| {
+                    stats
+                        .routes
+                        .get(&constructor)
+                        .map(|stats: &RldpSubscriberStats| stats.timeouts + stats.panics)
+                };
+                if (failed(1), failed(2)) == (Some(1), Some(1)) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        let stats = node.stats();
+        assert_eq!((stats.routes[&1].timeouts, stats.routes[&2].panics), (1, 1));
+        // Timed out subscriber is aborted
+        tokio::time::timeout(wait, async {
+            while !dropped.load(Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(answer_packets(&transport), 0);
+        // Node keeps serving
+        receive_query(&node, &peers, &[3, 0, 0, 0], 3).await;
+        tokio::time::timeout(wait, async {
+            while answer_packets(&transport) == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        node.shutdown(None).await;
+    }
+
+    // Answers once let through gate
+    struct GatedSubscriber {
+        calls: AtomicU64,
+        gate: tokio::sync::Semaphore,
+    }
+
+    #[async_trait::async_trait]
+    impl RldpSourceSubscriber for GatedSubscriber {
+        async fn try_answer(
+            &self,
+            _query: &RldpQuery,
+            _peers: &AdnlPeers,
+        ) -> Result<Option<Box<dyn RldpSendSource>>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.gate.acquire().await?.forget();
+            Ok(Some(Box::new(b"gated".to_vec())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_changed_during_query() {
+        let transport = Arc::new(TestTransport::default());
+        let node = RldpNode::with_transport(
+            transport.clone(),
+            Vec::new(),
+            Vec::new(),
+            RldpNodeOptions::default(),
+        );
+        let gated = Arc::new(GatedSubscriber {
+            calls: AtomicU64::new(0),
+            gate: tokio::sync::Semaphore::new(0),
+        });
+        let handle = node.add_source_subscriber(gated.clone());
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        receive_query(&node, &peers, b"query", 1).await;
+        let wait = Duration::from_secs(5);
+        tokio::time::timeout(wait, async {
+            while gated.calls.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        // Subscribers change while query is processed
+        assert!(node.remove_subscriber(&handle));
+        let added = node.add_source_subscriber(Arc::new(DataSubscriber(b"added".to_vec())));
+        gated.gate.add_permits(1);
+        // Query in flight completes on its snapshot
+        tokio::time::timeout(wait, async {
+            while answer_packets(&transport) == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        let stats = node.stats();
+        assert_eq!(stats.source_subscribers.len(), 1);
+        assert_eq!(stats.source_subscribers[0].handle, added);
+        assert_eq!(stats.source_subscribers[0].calls, 0);
+        // Next query does not reach removed subscriber
+        receive_query(&node, &peers, b"query", 2).await;
+        tokio::time::timeout(wait, async {
+            while node.stats().source_subscribers[0].answers == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(gated.calls.load(Ordering::Relaxed), 1);
+        node.shutdown(None).await;
+    }
+
+    // Answers outgoing queries if answer is set, records streamed outcome
+    #[derive(Debug, Default)]
+    struct StreamMiddleware {
+        answer: Option<Vec<u8>>,
+        streamed: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl RldpMiddleware for StreamMiddleware {
+        async fn before(&self, _query: &mut RldpQueryInfo) -> Option<RldpOutcome> {
+            self.answer.clone().map(RldpOutcome::Answer)
+        }
+
+        async fn after(&self, _query: &RldpQueryInfo, outcome: &mut RldpOutcome) {
+            if let RldpOutcome::Streamed = outcome {
+                self.streamed.store(true, Ordering::Relaxed)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_stream_with_middleware() {
+        use tokio::io::AsyncReadExt;
+
+        let middleware = Arc::new(StreamMiddleware::default());
+        let options = RldpNodeOptions {
+            middleware: vec![middleware.clone() as Arc<dyn RldpMiddleware>],
+            ..Default::default()
+        };
+        let transport = Arc::new(LoopbackTransport::default());
+        let node = RldpNode::with_transport(transport.clone(), Vec::new(), Vec::new(), options);
+        let data = test_data(100_000);
+        let subscribers: Vec<Arc<dyn RldpSourceSubscriber>> =
+            vec![Arc::new(DataSubscriber(data.clone()))];
+        let peer_transport = Arc::new(LoopbackTransport::default());
+        let peer = RldpNode::with_transport(
+            peer_transport.clone(),
+            Vec::new(),
+            subscribers,
+            RldpNodeOptions::default(),
+        );
+        *transport.peer.lock().unwrap() = Arc::downgrade(&peer);
+        *peer_transport.peer.lock().unwrap() = Arc::downgrade(&node);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let mut stream = node
+            .query_stream(b"query", None, &peers, None)
+            .await
+            .unwrap();
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer).await.unwrap();
+        assert!(answer == data);
+        // Answer is not buffered for middleware
+        assert!(middleware.streamed.load(Ordering::Relaxed));
+        node.shutdown(None).await;
+        peer.shutdown(None).await;
+    }
+
+    #[tokio::test]
+    async fn test_query_stream_answered_by_middleware() {
+        use tokio::io::AsyncReadExt;
+
+        let middleware = Arc::new(StreamMiddleware {
+            answer: Some(test_data(1000)),
+            ..Default::default()
+        });
+        let options = RldpNodeOptions {
+            middleware: vec![middleware.clone() as Arc<dyn RldpMiddleware>],
+            ..Default::default()
+        };
+        let transport = Arc::new(TestTransport::default());
+        let node = RldpNode::with_transport(transport.clone(), Vec::new(), Vec::new(), options);
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let mut stream = node
+            .query_stream(b"query", None, &peers, None)
+            .await
+            .unwrap();
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer).await.unwrap();
+        assert!(answer == test_data(1000));
+        assert!(transport.sent.lock().unwrap().is_empty());
+        assert!(!middleware.streamed.load(Ordering::Relaxed));
+        node.shutdown(None).await;
+    }
+}
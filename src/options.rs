@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use adnl::common::KeyId;
+
+use crate::{RldpAuthorizer, RldpClock, RldpMiddleware, RldpRng, RldpRuntimeClock, RldpThreadRng};
+
+/// Rldp Node options
+#[derive(Clone, Debug)]
+pub struct RldpNodeOptions {
+    /// Number of threads for RaptorQ encoding and decoding
+    pub codec_threads: usize,
+    /// Memory limit for cached RaptorQ encoders, bytes (0 to disable, default)
+    pub encoder_cache_size: usize,
+    /// Memory limit for cached answers to duplicate queries, bytes (0 to disable).
+    /// Only answers returned as data are cached, answers from send sources are not
+    pub answer_cache_size: usize,
+    /// Lifetime of cached answers, milliseconds
+    pub answer_cache_ttl_ms: u64,
+    /// Detect duplicate queries by data hash instead of query ID (for idempotent queries)
+    pub answer_cache_by_data: bool,
+    /// Share single transfer between concurrent identical queries to the same peer
+    pub coalesce_queries: bool,
+    /// Directory for answers spilled to disk
+    pub spill_dir: std::path::PathBuf,
+    /// Packets buffered per transfer, newer packets are dropped on overflow
+    pub transfer_queue_size: usize,
+    /// Send symbols of next part ahead of current one, for peers keeping them.
+    /// Off by default: other RLDP implementations drop symbols of parts ahead of
+    /// current one, so to them every such symbol is wasted bandwidth
+    pub send_lookahead: bool,
+    /// Bandwidth limit for answers, bytes per second (0 for no limit)
+    pub answer_bytes_per_sec: u64,
+    /// Bandwidth limit for answers to single peer, bytes per second (0 for no limit)
+    pub answer_bytes_per_sec_per_peer: u64,
+    /// Rate limit for served queries, per second (0 for no limit)
+    pub queries_per_sec: u64,
+    /// Rate limit for served queries from single peer, per second (0 for no limit)
+    pub queries_per_sec_per_peer: u64,
+    /// Limit of concurrent incoming transfers (0 for no limit)
+    pub max_incoming_transfers: usize,
+    /// Limit of concurrent incoming transfers from single peer (0 for no limit, default)
+    pub max_incoming_transfers_per_peer: usize,
+    /// Peers allowed to query this node (None for any peer)
+    pub allowed_peers: Option<Vec<Arc<KeyId>>>,
+    /// Peers not allowed to query this node
+    pub denied_peers: Vec<Arc<KeyId>>,
+    /// Authorization hook for incoming queries, checked after allow and deny lists
+    pub authorizer: Option<Arc<dyn RldpAuthorizer>>,
+    /// Answers in flight to start shedding new queries (0 for no limit)
+    pub max_answers_in_flight: usize,
+    /// Answer bytes in flight to start shedding new queries (0 for no limit)
+    pub max_answer_bytes_in_flight: u64,
+    /// Average subscriber latency to start shedding new queries, milliseconds (0 for no limit)
+    pub max_subscriber_latency_ms: u64,
+    /// Time limit for subscribers to process query, milliseconds (0 for no limit, default)
+    pub subscriber_timeout_ms: u64,
+    /// Penalty points to ban misbehaving peer, one point decays per second (0 to disable, default).
+    /// Custom messages not parsed as RLDP ones are penalized too, so enable it only
+    /// when no other subscriber of the ADNL node takes custom messages
+    pub ban_threshold: u32,
+    /// Duration of peer ban, milliseconds
+    pub ban_duration_ms: u64,
+    /// Middleware chain wrapping outgoing and served queries
+    pub middleware: Vec<Arc<dyn RldpMiddleware>>,
+    /// Clock for transfer timeouts and cache expiration
+    pub clock: Arc<dyn RldpClock>,
+    /// RNG for transfer and query IDs
+    pub rng: Arc<dyn RldpRng>,
+}
+
+impl Default for RldpNodeOptions {
+    fn default() -> Self {
+        Self {
+            codec_threads: std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+            encoder_cache_size: 0,
+            answer_cache_size: 0,
+            answer_cache_ttl_ms: 30000,
+            answer_cache_by_data: false,
+            coalesce_queries: false,
+            spill_dir: std::env::temp_dir(),
+            transfer_queue_size: 1024,
+            send_lookahead: false,
+            answer_bytes_per_sec: 0,
+            answer_bytes_per_sec_per_peer: 0,
+            queries_per_sec: 0,
+            queries_per_sec_per_peer: 0,
+            max_incoming_transfers: 4096,
+            max_incoming_transfers_per_peer: 0,
+            allowed_peers: None,
+            denied_peers: Vec::new(),
+            authorizer: None,
+            max_answers_in_flight: 0,
+            max_answer_bytes_in_flight: 0,
+            max_subscriber_latency_ms: 0,
+            subscriber_timeout_ms: 0,
+            ban_threshold: 0,
+            ban_duration_ms: 600000,
+            middleware: Vec::new(),
+            clock: Arc::new(RldpRuntimeClock),
+            rng: Arc::new(RldpThreadRng),
+        }
+    }
+}
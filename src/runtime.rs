@@ -0,0 +1,183 @@
+use std::future::Future;
+use std::time::Instant;
+
+use adnl::{common::AdnlPeers, node::AdnlNode};
+use rand::RngCore;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use ton_types::{fail, Result};
+
+/// Transport carrying RLDP packets between peers
+#[async_trait::async_trait]
+pub trait RldpTransport: Send + Sync {
+    /// Send custom message to peer
+    async fn send_custom(&self, data: &[u8], peers: &AdnlPeers) -> Result<()>;
+}
+
+#[async_trait::async_trait]
+impl RldpTransport for AdnlNode {
+    async fn send_custom(&self, data: &[u8], peers: &AdnlPeers) -> Result<()> {
+        AdnlNode::send_custom(self, data, peers).await
+    }
+}
+
+/// Source of time for RLDP transfers
+pub trait RldpClock: std::fmt::Debug + Send + Sync {
+    /// Current time
+    fn now(&self) -> Instant;
+}
+
+/// Clock of tokio runtime, follows paused time in tests
+#[derive(Debug, Default)]
+pub struct RldpRuntimeClock;
+
+impl RldpClock for RldpRuntimeClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+/// Source of random transfer and query IDs
+pub trait RldpRng: std::fmt::Debug + Send + Sync {
+    /// Fill buffer with random bytes
+    fn fill(&self, buf: &mut [u8]);
+}
+
+/// Thread-local RNG
+#[derive(Debug, Default)]
+pub struct RldpThreadRng;
+
+impl RldpRng for RldpThreadRng {
+    fn fill(&self, buf: &mut [u8]) {
+        rand::thread_rng().fill_bytes(buf)
+    }
+}
+
+/// Any RNG behind mutex, e.g. seeded one to reproduce IDs
+impl<R: RngCore + std::fmt::Debug + Send> RldpRng for std::sync::Mutex<R> {
+    fn fill(&self, buf: &mut [u8]) {
+        match self.lock() {
+            Ok(mut rng) => rng.fill_bytes(buf),
+            Err(e) => e.into_inner().fill_bytes(buf),
+        }
+    }
+}
+
+// Tasks spawned on behalf of node, stopped and awaited on its shutdown
+pub(crate) struct RldpTasks {
+    done: tokio::sync::Mutex<mpsc::Receiver<()>>,
+    sender: std::sync::Mutex<Option<mpsc::Sender<()>>>,
+    stop: watch::Sender<bool>,
+    stopped: watch::Receiver<bool>,
+}
+
+impl RldpTasks {
+    pub(crate) fn new() -> Self {
+        let (sender, done) = mpsc::channel(1);
+        let (stop, stopped) = watch::channel(false);
+        Self {
+            done: tokio::sync::Mutex::new(done),
+            sender: std::sync::Mutex::new(Some(sender)),
+            stop,
+            stopped,
+        }
+    }
+
+    // Task is aborted on stop, None is returned then
+    pub(crate) fn spawn<F>(&self, task: F) -> Result<JoinHandle<Option<F::Output>>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let guard = self.guard()?;
+        let mut stopped = self.stopped.clone();
+        Ok(tokio::spawn(async move {
+            let _guard = guard;
+            tokio::select! {
+                ret = task => Some(ret),
+                _ = Self::wait_stop(&mut stopped) => None,
+            }
+        }))
+    }
+
+    // Blocking job can not be aborted, so it is awaited on stop
+    pub(crate) fn spawn_blocking<F, R>(&self, job: F) -> Result<JoinHandle<R>>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let guard = self.guard()?;
+        Ok(tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            job()
+        }))
+    }
+
+    // No new tasks after stop, running ones are aborted
+    pub(crate) fn stop(&self) {
+        self.stop.send(true).ok();
+        if let Ok(mut sender) = self.sender.lock() {
+            sender.take();
+        }
+    }
+
+    // Awaits tasks stopped before
+    pub(crate) async fn wait(&self) {
+        let mut done = self.done.lock().await;
+        while done.recv().await.is_some() {}
+    }
+
+    fn guard(&self) -> Result<mpsc::Sender<()>> {
+        let guard = match self.sender.lock() {
+            Ok(sender) => sender.clone(),
+            Err(_) => None,
+        };
+        match guard {
+            Some(guard) => Ok(guard),
+            None => fail!("RLDP node is shut down"),
+        }
+    }
+
+    async fn wait_stop(stopped: &mut watch::Receiver<bool>) {
+        while !*stopped.borrow() {
+            if stopped.changed().await.is_err() {
+                std::future::pending::<()>().await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tasks_stopped() {
+        let tasks = RldpTasks::new();
+        let hung = tasks.spawn(std::future::pending::<()>()).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let job = tasks
+            .spawn_blocking({
+                let done = done.clone();
+                move || {
+                    std::thread::sleep(Duration::from_millis(50));
+                    done.store(true, Ordering::Relaxed)
+                }
+            })
+            .unwrap();
+        tasks.stop();
+        assert!(tasks.spawn(async {}).is_err());
+        assert!(tasks.spawn_blocking(|| ()).is_err());
+        tokio::time::timeout(Duration::from_secs(5), tasks.wait())
+            .await
+            .unwrap();
+        // Hung task is aborted, blocking job is completed
+        assert!(done.load(Ordering::Relaxed));
+        assert_eq!(hung.await.unwrap(), None);
+        job.await.unwrap();
+    }
+}
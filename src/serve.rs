@@ -0,0 +1,178 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use adnl::common::{deserialize, now, AdnlPeers};
+use ton_api::ton;
+use ton_api::ton::rldp::message::Query as RldpQuery;
+use ton_api::ton::rldp::Message as RldpMessageBoxed;
+use ton_types::{fail, Result};
+
+use crate::access::RldpAccess;
+use crate::cache::RldpAnswerCache;
+use crate::limits::{RldpLoad, RldpPenalties};
+use crate::middleware::RldpMiddlewareChain;
+use crate::subscribers::RldpSubscribers;
+use crate::{
+    RldpAnswerSource, RldpBytes, RldpClock, RldpOutcome, RldpQueryInfo, RldpSendSource, TransferId,
+    TARGET,
+};
+
+// Policy of served queries: authorization, answer cache, load shedding, middleware
+// and subscribers snapshot taken when transfer is started
+pub(crate) struct RldpQueryServer {
+    pub(crate) access: Arc<RldpAccess>,
+    pub(crate) answer_cache: Option<Arc<RldpAnswerCache>>,
+    pub(crate) clock: Arc<dyn RldpClock>,
+    pub(crate) load: Arc<RldpLoad>,
+    pub(crate) middleware: Arc<RldpMiddlewareChain>,
+    pub(crate) penalties: Arc<RldpPenalties>,
+    pub(crate) subscribers: Arc<RldpSubscribers>,
+}
+
+impl RldpQueryServer {
+    // Received query to be served, None if it is denied
+    pub(crate) fn accept(&self, data: &[u8], peers: &AdnlPeers) -> Result<Option<Arc<RldpQuery>>> {
+        let query: Arc<RldpQuery> = match Self::parse_query(data) {
+            Ok(query) => query.into(),
+            Err(e) => {
+                self.penalties.penalize(
+                    peers.other(),
+                    RldpPenalties::QUERY,
+                    &e.to_string(),
+                    self.clock.now(),
+                );
+                return Err(e);
+            }
+        };
+        if !self.access.authorize(peers.other(), &query) {
+            return Ok(None);
+        }
+        Ok(Some(query))
+    }
+
+    // Local deadline of query received at given time, peer clock is used for timeout only
+    pub(crate) fn deadline(query: &RldpQuery, received: Instant) -> Instant {
+        let timeout = std::cmp::max(query.timeout - now(), 0);
+        received + Duration::from_secs(timeout as u64)
+    }
+
+    // Query passed through middleware to subscribers
+    pub(crate) async fn serve(
+        &self,
+        mut query: Arc<RldpQuery>,
+        peers: &AdnlPeers,
+        transfer_id: &TransferId,
+        deadline: Instant,
+    ) -> RldpOutcome {
+        if self.middleware.is_empty() {
+            return self
+                .answer(&query, peers, transfer_id, deadline, false)
+                .await;
+        }
+        let mut info = RldpQueryInfo {
+            data: query.data.0.clone(),
+            deadline: query.timeout,
+            incoming: true,
+            max_answer_size: query.max_answer_size,
+            peers: peers.clone(),
+            query_id: query.query_id.0,
+        };
+        let (passed, outcome) = self.middleware.before(&mut info).await;
+        let outcome = match outcome {
+            Some(outcome) => outcome,
+            None => {
+                if info.data != query.data.0 {
+                    let mut rewritten = query.as_ref().clone();
+                    rewritten.data = ton::bytes(info.data.clone());
+                    query = Arc::new(rewritten)
+                }
+                self.answer(&query, peers, transfer_id, deadline, true)
+                    .await
+            }
+        };
+        self.middleware.after(passed, &info, outcome).await
+    }
+
+    // Answer framed for sending, None if there is nothing to send
+    pub(crate) fn answer_source(
+        query: &RldpQuery,
+        outcome: RldpOutcome,
+    ) -> Result<Option<Box<dyn RldpSendSource>>> {
+        let source: Box<dyn RldpSendSource> = match outcome {
+            RldpOutcome::Answer(answer) => Box::new(RldpBytes::from(answer)),
+            RldpOutcome::Source(source) => source,
+            RldpOutcome::Streamed => fail!("Unexpected streamed answer for served RLDP query"),
+            RldpOutcome::Empty => return Ok(None),
+            RldpOutcome::Error(e) => return Err(e),
+        };
+        let (len, max) = (source.size(), query.max_answer_size as usize);
+        if len > max {
+            fail!("Exceeded max RLDP answer size: {} vs {}", len, max)
+        }
+        let source = RldpAnswerSource::with_source(&query.query_id, source)?;
+        Ok(Some(Box::new(source)))
+    }
+
+    // Answer is kept as data when middleware is to see it, cached one is shared otherwise
+    async fn answer(
+        &self,
+        query: &Arc<RldpQuery>,
+        peers: &AdnlPeers,
+        transfer_id: &TransferId,
+        deadline: Instant,
+        inspected: bool,
+    ) -> RldpOutcome {
+        let cached = self
+            .answer_cache
+            .as_ref()
+            .map(|cache| (cache, cache.key(peers, query)));
+        if let Some((cache, key)) = &cached {
+            if let Some(answer) = cache.get(key, self.clock.now()) {
+                log::trace!(
+                    target: TARGET,
+                    "RLDP answer in transfer {} taken from cache",
+                    base64::encode(transfer_id)
+                );
+                return if inspected {
+                    RldpOutcome::Answer(answer.as_ref().clone())
+                } else {
+                    RldpOutcome::Source(Box::new(answer))
+                };
+            }
+        }
+        if !self
+            .load
+            .admit_query(peers.other(), deadline, self.clock.now())
+        {
+            return RldpOutcome::Empty;
+        }
+        let started = self.clock.now();
+        let outcome = self
+            .subscribers
+            .process(query, peers, self.clock.as_ref(), started)
+            .await;
+        self.load
+            .update_latency(self.clock.now().saturating_duration_since(started));
+        match (outcome, cached) {
+            (Ok(RldpOutcome::Answer(answer)), Some((cache, key))) if inspected => {
+                cache.insert(key, Arc::new(answer.clone()), self.clock.now());
+                RldpOutcome::Answer(answer)
+            }
+            (Ok(RldpOutcome::Answer(answer)), Some((cache, key))) => {
+                let answer = Arc::new(answer);
+                cache.insert(key, answer.clone(), self.clock.now());
+                RldpOutcome::Source(Box::new(answer))
+            }
+            (Ok(outcome), _) => outcome,
+            (Err(e), _) => RldpOutcome::Error(e),
+        }
+    }
+
+    fn parse_query(data: &[u8]) -> Result<Box<RldpQuery>> {
+        match deserialize(data)?.downcast::<RldpMessageBoxed>() {
+            Ok(RldpMessageBoxed::Rldp_Query(query)) => Ok(query),
+            Ok(message) => fail!("Unexpected RLDP message: {:?}", message),
+            Err(object) => fail!("Unexpected RLDP message: {:?}", object),
+        }
+    }
+}
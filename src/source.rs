@@ -0,0 +1,352 @@
+use std::sync::Arc;
+
+use adnl::common::serialize;
+use ton_api::ton::rldp::message::Answer as RldpAnswer;
+use ton_api::{ton, IntoBoxed};
+use ton_types::{fail, Result};
+
+use crate::tl::{truncate_tl_bytes, write_tl_bytes_len};
+
+/// Range of shared data, cloned and sliced without copying
+#[derive(Clone, Debug, Default)]
+pub struct RldpBytes {
+    data: Arc<Vec<u8>>,
+    end: usize,
+    start: usize,
+}
+
+impl RldpBytes {
+    /// Construct over range of shared data
+    pub fn with_range(data: Arc<Vec<u8>>, range: std::ops::Range<usize>) -> Result<Self> {
+        if (range.start > range.end) || (range.end > data.len()) {
+            fail!(
+                "Out of range read {}..{} from RLDP source",
+                range.start,
+                range.end
+            )
+        }
+        Ok(Self {
+            data,
+            end: range.end,
+            start: range.start,
+        })
+    }
+
+    /// Sub-range, relative to this one
+    pub fn slice(&self, range: std::ops::Range<usize>) -> Result<Self> {
+        if range.end > self.len() {
+            fail!(
+                "Out of range read {}..{} from RLDP source",
+                range.start,
+                range.end
+            )
+        }
+        Self::with_range(
+            self.data.clone(),
+            self.start + range.start..self.start + range.end,
+        )
+    }
+}
+
+impl std::ops::Deref for RldpBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+}
+
+impl From<Vec<u8>> for RldpBytes {
+    fn from(data: Vec<u8>) -> Self {
+        Self::from(Arc::new(data))
+    }
+}
+
+impl From<Arc<Vec<u8>>> for RldpBytes {
+    fn from(data: Arc<Vec<u8>>) -> Self {
+        Self {
+            end: data.len(),
+            data,
+            start: 0,
+        }
+    }
+}
+
+/// Source of data to be sent in RLDP transfer, read part by part in order
+#[async_trait::async_trait]
+pub trait RldpSendSource: Send {
+    /// Total size of data
+    fn size(&self) -> usize;
+    /// Read data range, exactly `len` bytes must be returned
+    async fn read(&mut self, offset: usize, len: usize) -> Result<RldpBytes>;
+}
+
+/// Data is copied on read, RldpBytes or Arc<Vec<u8>> is shared instead
+#[async_trait::async_trait]
+impl RldpSendSource for Vec<u8> {
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    async fn read(&mut self, offset: usize, len: usize) -> Result<RldpBytes> {
+        match self.get(offset..offset + len) {
+            Some(data) => Ok(data.to_vec().into()),
+            None => fail!(
+                "Out of range read {}..{} from RLDP source",
+                offset,
+                offset + len
+            ),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RldpSendSource for Arc<Vec<u8>> {
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    async fn read(&mut self, offset: usize, len: usize) -> Result<RldpBytes> {
+        RldpBytes::with_range(self.clone(), offset..offset + len)
+    }
+}
+
+#[async_trait::async_trait]
+impl RldpSendSource for RldpBytes {
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    async fn read(&mut self, offset: usize, len: usize) -> Result<RldpBytes> {
+        self.slice(offset..offset + len)
+    }
+}
+
+/// RLDP send source over async reader
+pub struct RldpReaderSource<R> {
+    offset: usize,
+    reader: R,
+    size: usize,
+}
+
+impl<R> RldpReaderSource<R> {
+    /// Construct over reader which has exactly `size` bytes to send
+    pub fn with_reader(reader: R, size: usize) -> Self {
+        Self {
+            offset: 0,
+            reader,
+            size,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: tokio::io::AsyncRead + Send + Unpin> RldpSendSource for RldpReaderSource<R> {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    async fn read(&mut self, offset: usize, len: usize) -> Result<RldpBytes> {
+        use tokio::io::AsyncReadExt;
+
+        if offset != self.offset {
+            fail!(
+                "Non-sequential read {} vs {} from RLDP source",
+                offset,
+                self.offset
+            )
+        }
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data).await?;
+        self.offset += len;
+        Ok(data.into())
+    }
+}
+
+/// RLDP send source over file range
+pub struct RldpFileSource {
+    file: tokio::fs::File,
+    size: usize,
+    start: u64,
+}
+
+impl RldpFileSource {
+    /// Open file to send `size` bytes from `start` position
+    pub async fn with_range(
+        path: impl AsRef<std::path::Path>,
+        start: u64,
+        size: usize,
+    ) -> Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+        if file.metadata().await?.len() < start + size as u64 {
+            fail!("File is too short for RLDP source range {}+{}", start, size)
+        }
+        Ok(Self { file, size, start })
+    }
+}
+
+#[async_trait::async_trait]
+impl RldpSendSource for RldpFileSource {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    async fn read(&mut self, offset: usize, len: usize) -> Result<RldpBytes> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        if offset + len > self.size {
+            fail!(
+                "Out of range read {}..{} from RLDP source",
+                offset,
+                offset + len
+            )
+        }
+        self.file
+            .seek(std::io::SeekFrom::Start(self.start + offset as u64))
+            .await?;
+        let mut data = vec![0; len];
+        self.file.read_exact(&mut data).await?;
+        Ok(data.into())
+    }
+}
+
+/// RLDP send source wrapping answer into rldp.answer TL framing: header, data, padding
+pub struct RldpAnswerSource {
+    header: Vec<u8>,
+    inner: Box<dyn RldpSendSource>,
+    size: usize,
+}
+
+impl RldpAnswerSource {
+    /// Construct over answer data to query
+    pub fn with_source(query_id: &ton::int256, inner: Box<dyn RldpSendSource>) -> Result<Self> {
+        let mut header = serialize(
+            &RldpAnswer {
+                query_id: ton::int256(query_id.0),
+                data: ton::bytes(Vec::new()),
+            }
+            .into_boxed(),
+        )?;
+        // Replace empty data with actual length prefix
+        truncate_tl_bytes(&mut header)?;
+        let len = inner.size();
+        write_tl_bytes_len(&mut header, len);
+        let size = (header.len() + len).div_ceil(4) * 4;
+        Ok(Self {
+            header,
+            inner,
+            size,
+        })
+    }
+
+    async fn read_inner(&mut self, offset: usize, len: usize) -> Result<RldpBytes> {
+        let data = self.inner.read(offset, len).await?;
+        if data.len() != len {
+            fail!("Wrong read size {} vs {} from RLDP source", data.len(), len)
+        }
+        Ok(data)
+    }
+}
+
+#[async_trait::async_trait]
+impl RldpSendSource for RldpAnswerSource {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    async fn read(&mut self, offset: usize, len: usize) -> Result<RldpBytes> {
+        if offset + len > self.size {
+            fail!(
+                "Out of range read {}..{} from RLDP source",
+                offset,
+                offset + len
+            )
+        }
+        let (header, inner) = (self.header.len(), self.inner.size());
+        // Range of data goes as is, only ranges with header or padding are assembled
+        if (offset >= header) && (offset + len <= header + inner) {
+            return self.read_inner(offset - header, len).await;
+        }
+        let mut data = Vec::with_capacity(len);
+        if offset < header {
+            let end = std::cmp::min(offset + len, header);
+            data.extend_from_slice(&self.header[offset..end])
+        }
+        if offset + len > header {
+            let start = offset.saturating_sub(header);
+            let end = std::cmp::min(offset + len - header, inner);
+            if start < end {
+                let inner = self.read_inner(start, end - start).await?;
+                data.extend_from_slice(&inner)
+            }
+        }
+        // Rest is TL padding
+        data.resize(len, 0);
+        Ok(data.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use adnl::common::deserialize;
+    use ton_api::ton::rldp::Message as RldpMessageBoxed;
+
+    use super::*;
+    use crate::testing::*;
+
+    // Returns less data than asked
+    struct ShortSource(usize);
+
+    #[async_trait::async_trait]
+    impl RldpSendSource for ShortSource {
+        fn size(&self) -> usize {
+            self.0
+        }
+
+        async fn read(&mut self, _offset: usize, len: usize) -> Result<RldpBytes> {
+            Ok(vec![1; len - 1].into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_answer_source_short_read() {
+        let query_id = ton::int256([1; 32]);
+        let mut source = RldpAnswerSource::with_source(&query_id, Box::new(test_data(10))).unwrap();
+        let size = source.size();
+        assert_eq!(size % 4, 0);
+        let answer = source.read(0, size).await.unwrap();
+        match deserialize(&answer).unwrap().downcast::<RldpMessageBoxed>() {
+            Ok(RldpMessageBoxed::Rldp_Answer(answer)) => assert_eq!(answer.data.0, test_data(10)),
+            _ => panic!("Answer expected"),
+        }
+        let mut source =
+            RldpAnswerSource::with_source(&query_id, Box::new(ShortSource(10))).unwrap();
+        let size = source.size();
+        assert!(source.read(0, size).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_answer_source_shares_data() {
+        let query_id = ton::int256([1; 32]);
+        let data = Arc::new(test_data(10_001));
+        let mut source = RldpAnswerSource::with_source(&query_id, Box::new(data.clone())).unwrap();
+        let header = source.header.len();
+        // Range of data is not copied
+        let inner = source.read(header + 100, 5000).await.unwrap();
+        assert!(Arc::ptr_eq(&inner.data, &data));
+        assert_eq!(&inner[..], &data[100..5100]);
+        // Ranges with header or padding are assembled
+        let head = source.read(0, header + 10).await.unwrap();
+        assert!(!Arc::ptr_eq(&head.data, &data));
+        assert_eq!(&head[header..], &data[..10]);
+        let size = source.size();
+        let padding = size - header - data.len();
+        assert!(padding > 0);
+        let tail = source.read(size - 8, 8).await.unwrap();
+        assert_eq!(&tail[..8 - padding], &data[data.len() - 8 + padding..]);
+        assert!(tail[8 - padding..].iter().all(|byte| *byte == 0));
+        let sliced = RldpBytes::from(data.clone()).slice(10..20).unwrap();
+        assert_eq!(&sliced.slice(5..10).unwrap()[..], &data[15..20]);
+        assert!(sliced.slice(5..11).is_err());
+    }
+}
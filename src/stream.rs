@@ -0,0 +1,401 @@
+use adnl::common::{serialize, QueryId};
+use rand::Rng;
+use tokio::sync::mpsc;
+use ton_api::ton::rldp::message::Answer as RldpAnswer;
+use ton_api::{ton, IntoBoxed};
+use ton_types::{fail, Result};
+
+use crate::tl::fill_tl_bytes;
+use crate::{RldpOutcome, TARGET};
+
+/// Answer to RLDP query, readable while being received
+pub struct RldpAnswerStream {
+    chunk: Vec<u8>,
+    offset: usize,
+    prefix: Vec<u8>,
+    reader: mpsc::Receiver<Result<Vec<u8>>>,
+    state: RldpAnswerStreamState,
+}
+
+enum RldpAnswerStreamState {
+    Header(Vec<u8>),
+    Payload(usize, usize),
+    Padding(usize),
+}
+
+impl RldpAnswerStream {
+    pub(crate) const PARTS: usize = 2; // Parts buffered for reader
+
+    // Stream of answer to query, answer is TL-serialized and its empty data (4 bytes)
+    // is stripped to get prefix to check
+    pub(crate) fn with_query(
+        query_id: &QueryId,
+        reader: mpsc::Receiver<Result<Vec<u8>>>,
+    ) -> Result<Self> {
+        let mut prefix = Self::empty_answer(query_id)?;
+        prefix.truncate(prefix.len() - 4);
+        Ok(Self::with_reader(prefix, reader))
+    }
+
+    // Whole answer for reader, framed as serialized RLDP answer
+    pub(crate) fn answer_chunk(query_id: &QueryId, outcome: RldpOutcome) -> Result<Vec<u8>> {
+        match outcome {
+            RldpOutcome::Answer(answer) => {
+                let mut chunk = Self::empty_answer(query_id)?;
+                fill_tl_bytes(&mut chunk, &answer)?;
+                Ok(chunk)
+            }
+            RldpOutcome::Source(_) | RldpOutcome::Streamed => {
+                fail!("Unexpected answer source for outgoing RLDP query")
+            }
+            RldpOutcome::Empty => fail!("No answer to RLDP query"),
+            RldpOutcome::Error(e) => Err(e),
+        }
+    }
+
+    fn empty_answer(query_id: &QueryId) -> Result<Vec<u8>> {
+        serialize(
+            &RldpAnswer {
+                query_id: ton::int256(*query_id),
+                data: ton::bytes(Vec::new()),
+            }
+            .into_boxed(),
+        )
+    }
+
+    fn with_reader(prefix: Vec<u8>, reader: mpsc::Receiver<Result<Vec<u8>>>) -> Self {
+        Self {
+            chunk: Vec::new(),
+            offset: 0,
+            prefix,
+            reader,
+            state: RldpAnswerStreamState::Header(Vec::new()),
+        }
+    }
+
+    // TL bytes length prefix: (header length, data length)
+    fn parse_header(header: &[u8], at: usize) -> Option<(usize, usize)> {
+        let len = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .rev()
+                .fold(0usize, |len, byte| (len << 8) | *byte as usize)
+        };
+        match header.get(at)? {
+            254 if header.len() >= at + 4 => Some((at + 4, len(&header[at + 1..at + 4]))),
+            255 if header.len() >= at + 8 => Some((at + 8, len(&header[at + 1..at + 8]))),
+            254 | 255 => None,
+            len => Some((at + 1, *len as usize)),
+        }
+    }
+
+    fn consume(&mut self, buf: &mut tokio::io::ReadBuf<'_>) -> std::io::Result<()> {
+        while self.offset < self.chunk.len() {
+            let input = &self.chunk[self.offset..];
+            match &mut self.state {
+                RldpAnswerStreamState::Header(header) => {
+                    header.push(input[0]);
+                    self.offset += 1;
+                    if header.len() <= self.prefix.len() {
+                        continue;
+                    }
+                    if let Some((header_len, len)) = Self::parse_header(header, self.prefix.len()) {
+                        if header[..self.prefix.len()] != self.prefix[..] {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                "Unexpected answer to RLDP query",
+                            ));
+                        }
+                        let padding = (4 - (header_len + len) % 4) % 4;
+                        self.state = if len > 0 {
+                            RldpAnswerStreamState::Payload(len, padding)
+                        } else {
+                            RldpAnswerStreamState::Padding(padding)
+                        }
+                    }
+                }
+                RldpAnswerStreamState::Payload(left, padding) => {
+                    let len = std::cmp::min(std::cmp::min(*left, input.len()), buf.remaining());
+                    if *left > 0 && len == 0 {
+                        break;
+                    }
+                    buf.put_slice(&input[..len]);
+                    self.offset += len;
+                    *left -= len;
+                    if *left == 0 {
+                        self.state = RldpAnswerStreamState::Padding(*padding)
+                    }
+                }
+                RldpAnswerStreamState::Padding(left) => {
+                    let len = std::cmp::min(*left, input.len());
+                    *left -= len;
+                    self.offset = if *left == 0 {
+                        self.chunk.len()
+                    } else {
+                        self.offset + len
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl tokio::io::AsyncRead for RldpAnswerStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        use std::task::Poll;
+
+        let stream = self.get_mut();
+        loop {
+            let filled = buf.filled().len();
+            stream.consume(buf)?;
+            if (buf.filled().len() > filled) || (buf.remaining() == 0) {
+                return Poll::Ready(Ok(()));
+            }
+            match stream.reader.poll_recv(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(chunk))) => {
+                    stream.chunk = chunk;
+                    stream.offset = 0;
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(Err(std::io::Error::other(e.to_string())))
+                }
+                Poll::Ready(None) => match stream.state {
+                    RldpAnswerStreamState::Padding(0) => return Poll::Ready(Ok(())),
+                    _ => return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into())),
+                },
+            }
+        }
+    }
+}
+
+/// Answer to RLDP query kept in memory or spilled to disk
+pub enum RldpAnswerData {
+    /// Answer in memory
+    Memory(Vec<u8>),
+    /// Answer in temporary file
+    File(RldpAnswerFile),
+}
+
+/// Temporary file with RLDP answer, removed on drop unless kept
+pub struct RldpAnswerFile {
+    keep: bool,
+    path: std::path::PathBuf,
+}
+
+impl RldpAnswerData {
+    // Answer bigger than threshold is spilled to temporary file in directory
+    pub(crate) async fn read_from(
+        stream: &mut RldpAnswerStream,
+        threshold: usize,
+        dir: &std::path::Path,
+    ) -> Result<Self> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut data = Vec::new();
+        (&mut *stream)
+            .take(threshold as u64 + 1)
+            .read_to_end(&mut data)
+            .await?;
+        if data.len() <= threshold {
+            return Ok(Self::Memory(data));
+        }
+        // File is removed on drop if anything fails below
+        let (answer, mut file) = RldpAnswerFile::create(dir).await?;
+        file.write_all(&data).await?;
+        drop(data);
+        tokio::io::copy(stream, &mut file).await?;
+        file.flush().await?;
+        log::trace!(
+            target: TARGET,
+            "RLDP answer spilled to {}",
+            answer.path().display()
+        );
+        Ok(Self::File(answer))
+    }
+}
+
+impl RldpAnswerFile {
+    async fn create(dir: &std::path::Path) -> Result<(Self, tokio::fs::File)> {
+        let name: [u8; 16] = rand::thread_rng().gen();
+        let path = dir.join(format!(
+            "rldp-{}.tmp",
+            base64::encode_config(&name, base64::URL_SAFE_NO_PAD)
+        ));
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        Ok((Self { keep: false, path }, file))
+    }
+
+    /// Path to file
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Keep file on disk, returns its path
+    pub fn keep(mut self) -> std::path::PathBuf {
+        self.keep = true;
+        self.path.clone()
+    }
+}
+
+impl RldpAnswerFile {
+    fn remove(path: &std::path::Path) {
+        if let Err(e) = std::fs::remove_file(path) {
+            log::warn!(
+                target: TARGET,
+                "Cannot remove RLDP answer file {}: {}",
+                path.display(),
+                e
+            )
+        }
+    }
+}
+
+impl Drop for RldpAnswerFile {
+    // File is removed off runtime threads when dropped inside runtime
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        let path = std::mem::take(&mut self.path);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || Self::remove(&path));
+            }
+            Err(_) => Self::remove(&path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::tl::truncate_tl_bytes;
+
+    #[tokio::test]
+    async fn test_answer_stream_byte_chunks() {
+        use tokio::io::AsyncReadExt;
+
+        for len in TL_BYTES_LENGTHS {
+            let data = test_data(len);
+            let serialized = serialized_answer(&data);
+            let mut prefix = serialized_answer(&[]);
+            truncate_tl_bytes(&mut prefix).unwrap();
+            let (sender, reader) = mpsc::channel(RldpAnswerStream::PARTS);
+            // Big payload goes in one chunk, framing around it byte by byte
+            let chunks = if len > 4096 {
+                let (head, rest) = serialized.split_at(64);
+                let (body, tail) = rest.split_at(rest.len() - 8);
+                let mut chunks: Vec<Vec<u8>> = head.iter().map(|byte| vec![*byte]).collect();
+                chunks.push(body.to_vec());
+                chunks.extend(tail.iter().map(|byte| vec![*byte]));
+                chunks
+            } else {
+                serialized.iter().map(|byte| vec![*byte]).collect()
+            };
+            tokio::spawn(async move {
+                for chunk in chunks {
+                    sender.send(Ok(chunk)).await.ok();
+                }
+            });
+            let mut stream = RldpAnswerStream::with_reader(prefix, reader);
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert!(received == data, "length {}", len);
+        }
+        // Stream ending inside padding is incomplete
+        let serialized = serialized_answer(&test_data(5));
+        let mut prefix = serialized_answer(&[]);
+        truncate_tl_bytes(&mut prefix).unwrap();
+        let (sender, reader) = mpsc::channel(RldpAnswerStream::PARTS);
+        sender
+            .send(Ok(serialized[..serialized.len() - 1].to_vec()))
+            .await
+            .unwrap();
+        drop(sender);
+        let mut stream = RldpAnswerStream::with_reader(prefix, reader);
+        assert!(stream.read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    // Stream of answer with data, cut before its end if incomplete
+    fn answer_stream(data: &[u8], complete: bool) -> RldpAnswerStream {
+        let mut serialized = serialized_answer(data);
+        if !complete {
+            serialized.truncate(serialized.len() / 2)
+        }
+        let mut prefix = serialized_answer(&[]);
+        truncate_tl_bytes(&mut prefix).unwrap();
+        let (sender, reader) = mpsc::channel(RldpAnswerStream::PARTS);
+        tokio::spawn(async move {
+            for chunk in serialized.chunks(1000) {
+                sender.send(Ok(chunk.to_vec())).await.ok();
+            }
+        });
+        RldpAnswerStream::with_reader(prefix, reader)
+    }
+
+    async fn spill_files(dir: &std::path::Path) -> usize {
+        let mut entries = tokio::fs::read_dir(dir).await.unwrap();
+        let mut count = 0;
+        while entries.next_entry().await.unwrap().is_some() {
+            count += 1
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn test_answer_spilled() {
+        let dir = std::env::temp_dir().join(format!("rldp-spill-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        // Answer up to threshold stays in memory
+        let data = test_data(1000);
+        let mut stream = answer_stream(&data, true);
+        match RldpAnswerData::read_from(&mut stream, 1000, &dir)
+            .await
+            .unwrap()
+        {
+            RldpAnswerData::Memory(answer) => assert!(answer == data),
+            RldpAnswerData::File(_) => panic!("answer is spilled"),
+        }
+        assert_eq!(spill_files(&dir).await, 0);
+        // Bigger answer goes to file, removed on drop
+        let data = test_data(10000);
+        let mut stream = answer_stream(&data, true);
+        let answer = match RldpAnswerData::read_from(&mut stream, 1000, &dir)
+            .await
+            .unwrap()
+        {
+            RldpAnswerData::File(answer) => answer,
+            RldpAnswerData::Memory(_) => panic!("answer is not spilled"),
+        };
+        assert!(answer.path().starts_with(&dir));
+        assert!(tokio::fs::read(answer.path()).await.unwrap() == data);
+        let path = answer.keep();
+        assert_eq!(spill_files(&dir).await, 1);
+        tokio::fs::remove_file(&path).await.unwrap();
+        // File of incomplete answer is removed
+        let mut stream = answer_stream(&data, false);
+        assert!(RldpAnswerData::read_from(&mut stream, 1000, &dir)
+            .await
+            .is_err());
+        let removed = async {
+            while spill_files(&dir).await > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), removed)
+            .await
+            .unwrap();
+        tokio::fs::remove_dir(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use adnl::common::{AdnlPeers, Query, Subscriber};
+use dashmap::DashMap;
+use ton_api::ton::rldp::message::Query as RldpQuery;
+use ton_types::{fail, Result};
+
+use crate::runtime::RldpTasks;
+use crate::{RldpClock, RldpNodeOptions, RldpNodeStats, RldpOutcome, RldpSendSource};
+
+type RldpHandler = (RldpSubscriberHandle, Arc<dyn RldpSourceSubscriber>);
+
+/// Handle of registered subscriber
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RldpSubscriberHandle(u64);
+
+// Registered subscribers, replaced as whole on change, so queries in progress keep their snapshot
+#[derive(Clone)]
+pub(crate) struct RldpSubscribers {
+    next_handle: u64,
+    query: Vec<Arc<RldpSubscriber<dyn Subscriber>>>,
+    ranges: Vec<(RangeInclusive<u32>, RldpHandler)>,
+    route_metrics: Arc<DashMap<u32, Arc<RldpRouteMetrics>>>,
+    routes: HashMap<u32, RldpHandler>,
+    source: Vec<Arc<RldpSubscriber<dyn RldpSourceSubscriber>>>,
+    tasks: Arc<RldpTasks>,
+    timeout: Option<Duration>,
+    unhandled: Arc<AtomicU64>,
+}
+
+impl RldpSubscribers {
+    const MAX_ROUTE_TYPES: usize = 4096; // Query types with own statistics
+
+    pub(crate) fn with_options(options: &RldpNodeOptions, tasks: Arc<RldpTasks>) -> Self {
+        Self {
+            next_handle: 0,
+            query: Vec::new(),
+            ranges: Vec::new(),
+            route_metrics: Arc::new(DashMap::new()),
+            routes: HashMap::new(),
+            source: Vec::new(),
+            tasks,
+            timeout: match options.subscriber_timeout_ms {
+                0 => None,
+                timeout => Some(Duration::from_millis(timeout)),
+            },
+            unhandled: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub(crate) fn add_range(
+        &mut self,
+        constructors: RangeInclusive<u32>,
+        handler: Arc<dyn RldpSourceSubscriber>,
+    ) -> RldpSubscriberHandle {
+        let handle = self.handle();
+        self.ranges.push((constructors, (handle, handler)));
+        handle
+    }
+
+    pub(crate) fn add_route(
+        &mut self,
+        constructor: u32,
+        handler: Arc<dyn RldpSourceSubscriber>,
+    ) -> Result<RldpSubscriberHandle> {
+        if self.routes.contains_key(&constructor) {
+            fail!(
+                "RLDP route for constructor {:08x} is already registered",
+                constructor
+            )
+        }
+        let handle = self.handle();
+        self.routes.insert(constructor, (handle, handler));
+        Ok(handle)
+    }
+
+    pub(crate) fn add_query(&mut self, subscriber: Arc<dyn Subscriber>) -> RldpSubscriberHandle {
+        let handle = self.handle();
+        self.query
+            .push(Arc::new(RldpSubscriber::new(handle, subscriber)));
+        handle
+    }
+
+    pub(crate) fn add_source(
+        &mut self,
+        subscriber: Arc<dyn RldpSourceSubscriber>,
+    ) -> RldpSubscriberHandle {
+        let handle = self.handle();
+        self.source
+            .push(Arc::new(RldpSubscriber::new(handle, subscriber)));
+        handle
+    }
+
+    fn handle(&mut self) -> RldpSubscriberHandle {
+        self.next_handle += 1;
+        RldpSubscriberHandle(self.next_handle)
+    }
+
+    pub(crate) fn remove(&mut self, handle: &RldpSubscriberHandle) -> bool {
+        let count = self.count();
+        self.query.retain(|subscriber| subscriber.handle != *handle);
+        self.ranges.retain(|(_, (route, _))| route != handle);
+        self.routes.retain(|_, (route, _)| route != handle);
+        self.source
+            .retain(|subscriber| subscriber.handle != *handle);
+        count != self.count()
+    }
+
+    fn count(&self) -> usize {
+        self.query.len() + self.ranges.len() + self.routes.len() + self.source.len()
+    }
+
+    // Exact constructor ID is looked up first, then ranges in order of registration
+    fn route(&self, query: &RldpQuery) -> Option<(u32, &RldpHandler)> {
+        if self.routes.is_empty() && self.ranges.is_empty() {
+            return None;
+        }
+        let constructor = match query.data.0.get(..4) {
+            Some(&[b0, b1, b2, b3]) => u32::from_le_bytes([b0, b1, b2, b3]),
+            _ => return None,
+        };
+        let handler = self.routes.get(&constructor).or_else(|| {
+            self.ranges
+                .iter()
+                .find(|(constructors, _)| constructors.contains(&constructor))
+                .map(|(_, handler)| handler)
+        })?;
+        Some((constructor, handler))
+    }
+
+    // Metrics are kept per query type, types beyond limit are not accounted
+    fn route_metrics(
+        &self,
+        constructor: u32,
+        handle: RldpSubscriberHandle,
+    ) -> Arc<RldpRouteMetrics> {
+        let metrics = match self.route_metrics.get(&constructor) {
+            Some(metrics) => metrics.clone(),
+            None if self.route_metrics.len() >= Self::MAX_ROUTE_TYPES => {
+                Arc::new(RldpRouteMetrics::default())
+            }
+            None => self.route_metrics.entry(constructor).or_default().clone(),
+        };
+        metrics.handle.store(handle.0, Ordering::Relaxed);
+        metrics
+    }
+
+    // Routed handler is tried first, then source subscribers, then subscribers
+    pub(crate) async fn process(
+        &self,
+        query: &Arc<RldpQuery>,
+        peers: &AdnlPeers,
+        clock: &dyn RldpClock,
+        started: Instant,
+    ) -> Result<RldpOutcome> {
+        let deadline = self.timeout.map(|timeout| started + timeout);
+        if let Some((constructor, (handle, handler))) = self.route(query) {
+            let metrics = self.route_metrics(constructor, *handle);
+            let call = RldpSubscriber::answer_call(handler, query, peers);
+            let source = metrics
+                .metrics
+                .call(call, deadline, clock, &self.tasks)
+                .await?;
+            if let Some(source) = source {
+                return Ok(RldpOutcome::Source(source));
+            }
+        }
+        for subscriber in self.source.iter() {
+            let source = subscriber
+                .try_answer(query, peers, deadline, clock, &self.tasks)
+                .await?;
+            if let Some(source) = source {
+                return Ok(RldpOutcome::Source(source));
+            }
+        }
+        for subscriber in self.query.iter() {
+            // Subscribers are called one by one to account each of them
+            let call = {
+                let (peers, query) = (peers.clone(), query.clone());
+                let subscribers = vec![subscriber.subscriber.clone()];
+                async move {
+                    match Query::process_rldp(&subscribers, &query, &peers).await? {
+                        (true, answer) => Ok(Some(answer)),
+                        (false, _) => Ok(None),
+                    }
+                }
+            };
+            match subscriber
+                .metrics
+                .call(call, deadline, clock, &self.tasks)
+                .await?
+            {
+                Some(Some(answer)) => return Ok(RldpOutcome::Answer(answer.data.0)),
+                Some(None) => return Ok(RldpOutcome::Empty),
+                None => (),
+            }
+        }
+        self.unhandled.fetch_add(1, Ordering::Relaxed);
+        fail!("No subscribers for query {:?}", query)
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        stats.subscribers = self.query.iter().map(|s| s.stats()).collect();
+        stats.source_subscribers = self.source.iter().map(|s| s.stats()).collect();
+        stats.routes = self
+            .route_metrics
+            .iter()
+            .map(|metrics| {
+                let handle = RldpSubscriberHandle(metrics.handle.load(Ordering::Relaxed));
+                (*metrics.key(), metrics.metrics.stats(handle))
+            })
+            .collect::<BTreeMap<_, _>>();
+        stats.queries_unhandled = self.unhandled.load(Ordering::Relaxed);
+    }
+}
+
+struct RldpSubscriber<S: ?Sized> {
+    handle: RldpSubscriberHandle,
+    metrics: RldpSubscriberMetrics,
+    subscriber: Arc<S>,
+}
+
+impl<S: ?Sized> RldpSubscriber<S> {
+    fn new(handle: RldpSubscriberHandle, subscriber: Arc<S>) -> Self {
+        Self {
+            handle,
+            metrics: RldpSubscriberMetrics::default(),
+            subscriber,
+        }
+    }
+
+    fn stats(&self) -> RldpSubscriberStats {
+        self.metrics.stats(self.handle)
+    }
+}
+
+impl RldpSubscriber<dyn RldpSourceSubscriber> {
+    async fn try_answer(
+        &self,
+        query: &Arc<RldpQuery>,
+        peers: &AdnlPeers,
+        deadline: Option<Instant>,
+        clock: &dyn RldpClock,
+        tasks: &RldpTasks,
+    ) -> Result<Option<Box<dyn RldpSendSource>>> {
+        let call = Self::answer_call(&self.subscriber, query, peers);
+        self.metrics.call(call, deadline, clock, tasks).await
+    }
+
+    fn answer_call(
+        subscriber: &Arc<dyn RldpSourceSubscriber>,
+        query: &Arc<RldpQuery>,
+        peers: &AdnlPeers,
+    ) -> impl std::future::Future<Output = Result<Option<Box<dyn RldpSendSource>>>> + Send + 'static
+    {
+        let (peers, query) = (peers.clone(), query.clone());
+        let subscriber = subscriber.clone();
+        async move { subscriber.try_answer(&query, &peers).await }
+    }
+}
+
+// Routed handler metrics of query type, handle is of handler last served it
+#[derive(Default)]
+struct RldpRouteMetrics {
+    handle: AtomicU64,
+    metrics: RldpSubscriberMetrics,
+}
+
+#[derive(Default)]
+struct RldpSubscriberMetrics {
+    answers: AtomicU64,
+    calls: AtomicU64,
+    errors: AtomicU64,
+    latency: AtomicU64, // Milliseconds in total
+    panics: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+// Spawned task is aborted when awaiting is given up, e.g. on timeout or shutdown
+struct RldpTaskGuard<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for RldpTaskGuard<T> {
+    fn drop(&mut self) {
+        self.0.abort()
+    }
+}
+
+impl RldpSubscriberMetrics {
+    // Subscriber runs in separate task to survive its panic and to be aborted on timeout,
+    // node shutdown aborts and awaits it
+    async fn call<T: Send + 'static>(
+        &self,
+        call: impl std::future::Future<Output = Result<Option<T>>> + Send + 'static,
+        deadline: Option<Instant>,
+        clock: &dyn RldpClock,
+        tasks: &RldpTasks,
+    ) -> Result<Option<T>> {
+        let mut task = RldpTaskGuard(tasks.spawn(call)?);
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        let started = clock.now();
+        let ret = match deadline {
+            // Deadline is on injected clock, only remaining time is known to runtime
+            Some(deadline) => {
+                let wait = deadline.saturating_duration_since(started);
+                tokio::time::timeout(wait, &mut task.0).await
+            }
+            None => Ok((&mut task.0).await),
+        };
+        let elapsed = clock.now().saturating_duration_since(started);
+        self.latency
+            .fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+        match ret {
+            Ok(Ok(Some(Ok(Some(answer))))) => {
+                self.answers.fetch_add(1, Ordering::Relaxed);
+                Ok(Some(answer))
+            }
+            Ok(Ok(Some(Ok(None)))) => Ok(None),
+            Ok(Ok(None)) => fail!("RLDP node is shut down"),
+            Ok(Ok(Some(Err(e)))) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+            Ok(Err(e)) => {
+                self.panics.fetch_add(1, Ordering::Relaxed);
+                fail!("RLDP subscriber failed: {}", e)
+            }
+            Err(_) => {
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+                fail!("RLDP subscriber timed out in {} ms", elapsed.as_millis())
+            }
+        }
+    }
+
+    fn stats(&self, handle: RldpSubscriberHandle) -> RldpSubscriberStats {
+        let calls = self.calls.load(Ordering::Relaxed);
+        RldpSubscriberStats {
+            handle,
+            answers: self.answers.load(Ordering::Relaxed),
+            calls,
+            errors: self.errors.load(Ordering::Relaxed),
+            latency_ms: self.latency.load(Ordering::Relaxed) / std::cmp::max(calls, 1),
+            panics: self.panics.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Subscriber answering RLDP queries with data from send source
+#[async_trait::async_trait]
+pub trait RldpSourceSubscriber: Send + Sync {
+    /// Try to answer query, None if query is not consumed
+    async fn try_answer(
+        &self,
+        query: &RldpQuery,
+        peers: &AdnlPeers,
+    ) -> Result<Option<Box<dyn RldpSendSource>>>;
+}
+
+/// Subscriber statistics
+#[derive(Clone, Debug, Default)]
+pub struct RldpSubscriberStats {
+    /// Handle of subscriber
+    pub handle: RldpSubscriberHandle,
+    /// Queries answered
+    pub answers: u64,
+    /// Queries passed to subscriber
+    pub calls: u64,
+    /// Queries failed with error
+    pub errors: u64,
+    /// Average processing time, milliseconds
+    pub latency_ms: u64,
+    /// Queries failed with panic
+    pub panics: u64,
+    /// Queries timed out
+    pub timeouts: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use adnl::common::KeyId;
+
+    use super::*;
+    use crate::testing::*;
+
+    async fn routed_size(subscribers: &RldpSubscribers, data: &[u8]) -> Result<usize> {
+        let clock = TestClock::new();
+        let peers = AdnlPeers::with_keys(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+        let query = Arc::new(test_query(data));
+        match subscribers
+            .process(&query, &peers, clock.as_ref(), clock.now())
+            .await?
+        {
+            RldpOutcome::Source(source) => Ok(source.size()),
+            _ => panic!("Source answer expected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let mut subscribers =
+            RldpSubscribers::with_options(&RldpNodeOptions::default(), Arc::new(RldpTasks::new()));
+        let range = subscribers.add_range(0..=10, Arc::new(DataSubscriber(vec![1; 1])));
+        let exact = subscribers
+            .add_route(5, Arc::new(DataSubscriber(vec![2; 2])))
+            .unwrap();
+        assert!(subscribers
+            .add_route(5, Arc::new(DataSubscriber(vec![3; 3])))
+            .is_err());
+        // Exact route beats range registered before it
+        assert_eq!(
+            routed_size(&subscribers, &[5, 0, 0, 0, 9]).await.unwrap(),
+            2
+        );
+        assert_eq!(routed_size(&subscribers, &[6, 0, 0, 0]).await.unwrap(), 1);
+        assert_eq!(routed_size(&subscribers, &[6, 0, 0, 0]).await.unwrap(), 1);
+        // Query shorter than constructor ID is not routed
+        assert!(routed_size(&subscribers, &[5, 0, 0]).await.is_err());
+        assert!(routed_size(&subscribers, &[11, 0, 0, 0]).await.is_err());
+        let mut stats = RldpNodeStats::default();
+        subscribers.fill_stats(&mut stats);
+        assert_eq!(stats.queries_unhandled, 2);
+        assert_eq!(stats.routes.keys().copied().collect::<Vec<_>>(), vec![5, 6]);
+        assert_eq!(
+            (stats.routes[&5].handle, stats.routes[&5].answers),
+            (exact, 1)
+        );
+        assert_eq!(
+            (stats.routes[&6].handle, stats.routes[&6].answers),
+            (range, 2)
+        );
+        // Removed route frees its ID
+        assert!(subscribers.remove(&exact));
+        assert_eq!(routed_size(&subscribers, &[5, 0, 0, 0]).await.unwrap(), 1);
+        subscribers
+            .add_route(5, Arc::new(DataSubscriber(vec![3; 3])))
+            .unwrap();
+        assert_eq!(routed_size(&subscribers, &[5, 0, 0, 0]).await.unwrap(), 3);
+    }
+}
@@ -0,0 +1,354 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use dashmap::DashMap;
+use tokio::sync::mpsc;
+use ton_api::ton::rldp::MessagePart as RldpMessagePartBoxed;
+
+use crate::{RldpClock, RldpNodeOptions, RldpNodeStats, TransferId, TARGET};
+
+pub(crate) enum RldpTransfer {
+    Active(Arc<RldpTransferQueue>),
+    Done(u64), // Milliseconds since table creation
+}
+
+// Packets of active transfer, last use is tracked to expire stuck transfers
+pub(crate) struct RldpTransferQueue {
+    sender: mpsc::Sender<RldpMessagePartBoxed>,
+    touched: AtomicU64,
+}
+
+// Transfer table, entries are expired by janitor. Table holds the only references
+// to queues, so owning task sees its queue closed once its entry is replaced
+pub(crate) struct RldpTransfers {
+    clock: Arc<dyn RldpClock>,
+    dropped: AtomicU64,
+    epoch: Instant,
+    expired: AtomicU64,
+    janitor: AtomicBool,
+    queue_size: usize,
+    pub(crate) table: DashMap<TransferId, RldpTransfer>,
+    wheel: std::sync::Mutex<RldpTimerWheel>,
+}
+
+impl RldpTransfers {
+    pub(crate) const LINGER: u64 = 20000; // Milliseconds, done transfer confirms late packets
+    pub(crate) const STUCK: u64 = 30000; // Milliseconds without packets
+    pub(crate) const TICK: u64 = 100; // Milliseconds
+
+    pub(crate) fn with_options(options: &RldpNodeOptions) -> Self {
+        Self {
+            clock: options.clock.clone(),
+            dropped: AtomicU64::new(0),
+            epoch: options.clock.now(),
+            expired: AtomicU64::new(0),
+            janitor: AtomicBool::new(false),
+            queue_size: std::cmp::max(options.transfer_queue_size, 1),
+            table: DashMap::new(),
+            wheel: std::sync::Mutex::new(RldpTimerWheel::new()),
+        }
+    }
+
+    pub(crate) fn queue(&self) -> (Arc<RldpTransferQueue>, mpsc::Receiver<RldpMessagePartBoxed>) {
+        let (sender, reader) = mpsc::channel(self.queue_size);
+        let queue = RldpTransferQueue {
+            sender,
+            touched: AtomicU64::new(self.millis()),
+        };
+        (Arc::new(queue), reader)
+    }
+
+    // Expired or cleared on shutdown transfer is not brought back
+    pub(crate) fn done(&self, transfer_id: &TransferId) {
+        let now = self.millis();
+        match self.table.get_mut(transfer_id) {
+            Some(mut transfer) => *transfer = RldpTransfer::Done(now),
+            None => return,
+        }
+        self.schedule(transfer_id, now + Self::LINGER)
+    }
+
+    pub(crate) fn insert(&self, transfer_id: &TransferId, queue: &Arc<RldpTransferQueue>) {
+        let active = RldpTransfer::Active(queue.clone());
+        if self.table.insert(*transfer_id, active).is_none() {
+            self.schedule(transfer_id, self.millis() + Self::STUCK)
+        }
+    }
+
+    pub(crate) fn try_insert(
+        &self,
+        transfer_id: &TransferId,
+        queue: &Arc<RldpTransferQueue>,
+    ) -> bool {
+        use dashmap::mapref::entry::Entry;
+
+        match self.table.entry(*transfer_id) {
+            Entry::Vacant(entry) => entry.insert(RldpTransfer::Active(queue.clone())),
+            Entry::Occupied(_) => return false,
+        };
+        self.schedule(transfer_id, self.millis() + Self::STUCK);
+        true
+    }
+
+    // Newest packet is dropped on overflow, FEC and periodic confirms recover lost packets
+    pub(crate) fn push(&self, queue: &RldpTransferQueue, packet: RldpMessagePartBoxed) {
+        queue.touched.store(self.millis(), Ordering::Relaxed);
+        if let Err(mpsc::error::TrySendError::Full(_)) = queue.sender.try_send(packet) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn expire(&self) {
+        let now = self.millis();
+        let mut expired = Vec::new();
+        if let Ok(mut wheel) = self.wheel.lock() {
+            wheel.advance(now / Self::TICK, &mut expired)
+        }
+        for transfer_id in expired {
+            let expire = match self.table.get(&transfer_id) {
+                Some(transfer) => Self::expiration(transfer.value()),
+                None => continue,
+            };
+            if expire > now {
+                self.schedule(&transfer_id, expire);
+                continue;
+            }
+            // Stuck transfer lingers as done one, its task is stopped by closed queue
+            let mut stuck = false;
+            if let Some(mut transfer) = self.table.get_mut(&transfer_id) {
+                if let RldpTransfer::Active(_) = transfer.value() {
+                    if Self::expiration(transfer.value()) <= now {
+                        *transfer = RldpTransfer::Done(now);
+                        stuck = true
+                    }
+                }
+            }
+            if stuck {
+                self.expired.fetch_add(1, Ordering::Relaxed);
+                self.schedule(&transfer_id, now + Self::LINGER);
+                log::warn!(
+                    target: TARGET,
+                    "Stuck RLDP transfer {} expired",
+                    base64::encode(&transfer_id)
+                );
+                continue;
+            }
+            self.table.remove_if(&transfer_id, |_, transfer| {
+                Self::expiration(transfer) <= now
+            });
+        }
+    }
+
+    pub(crate) fn fill_stats(&self, stats: &mut RldpNodeStats) {
+        stats.transfers = self.table.len() as u64;
+        stats.transfers_expired = self.expired.load(Ordering::Relaxed);
+        stats.packets_dropped = self.dropped.load(Ordering::Relaxed);
+    }
+
+    // Single janitor expires entries, true for the caller which is to start it
+    pub(crate) fn start_janitor(&self) -> bool {
+        !self.janitor.swap(true, Ordering::Relaxed)
+    }
+
+    fn expiration(transfer: &RldpTransfer) -> u64 {
+        match transfer {
+            RldpTransfer::Active(queue) => queue.touched.load(Ordering::Relaxed) + Self::STUCK,
+            RldpTransfer::Done(done) => done + Self::LINGER,
+        }
+    }
+
+    fn millis(&self) -> u64 {
+        self.clock
+            .now()
+            .saturating_duration_since(self.epoch)
+            .as_millis() as u64
+    }
+
+    fn schedule(&self, transfer_id: &TransferId, expire: u64) {
+        if let Ok(mut wheel) = self.wheel.lock() {
+            wheel.schedule(transfer_id, expire.div_ceil(Self::TICK))
+        }
+    }
+}
+
+// Hashed timer wheel, slot keeps entries of all rounds checked by tick
+struct RldpTimerWheel {
+    slots: Vec<Vec<(TransferId, u64)>>,
+    tick: u64,
+}
+
+impl RldpTimerWheel {
+    const SLOTS: usize = 256;
+
+    fn new() -> Self {
+        Self {
+            slots: vec![Vec::new(); Self::SLOTS],
+            tick: 0,
+        }
+    }
+
+    fn advance(&mut self, tick: u64, expired: &mut Vec<TransferId>) {
+        // Every slot is visited at most once even if clock jumps far ahead
+        let steps = std::cmp::min(tick.saturating_sub(self.tick), Self::SLOTS as u64);
+        for _ in 0..steps {
+            self.tick += 1;
+            self.slots[(self.tick % Self::SLOTS as u64) as usize].retain(|(transfer_id, at)| {
+                if *at <= tick {
+                    expired.push(*transfer_id);
+                    false
+                } else {
+                    true
+                }
+            })
+        }
+        self.tick = std::cmp::max(self.tick, tick);
+    }
+
+    fn schedule(&mut self, transfer_id: &TransferId, tick: u64) {
+        let tick = std::cmp::max(tick, self.tick + 1);
+        self.slots[(tick % Self::SLOTS as u64) as usize].push((*transfer_id, tick))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_api::ton::rldp::messagepart::Complete as RldpComplete;
+    use ton_api::{ton, IntoBoxed};
+
+    use super::*;
+    use crate::testing::*;
+
+    fn test_transfers(clock: &Arc<TestClock>) -> RldpTransfers {
+        RldpTransfers::with_options(&RldpNodeOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_transfers_expire_after_clock_jump() {
+        let clock = TestClock::new();
+        let transfers = test_transfers(&clock);
+        let (queue, _reader) = transfers.queue();
+        transfers.insert(&[1; 32], &queue);
+        transfers.insert(&[2; 32], &queue);
+        transfers.done(&[2; 32]);
+        // Clock jumps over several rounds of wheel at once
+        let rounds = RldpTimerWheel::SLOTS as u64 * RldpTransfers::TICK * 3;
+        clock.advance(RldpTransfers::STUCK + rounds);
+        transfers.expire();
+        assert_eq!(transfers.table.len(), 1);
+        assert!(matches!(
+            transfers.table.get(&[1; 32]).as_deref(),
+            Some(RldpTransfer::Done(_))
+        ));
+        assert_eq!(transfers.expired.load(Ordering::Relaxed), 1);
+        // Wheel goes on from new time
+        transfers.insert(&[3; 32], &queue);
+        clock.advance(RldpTransfers::STUCK - RldpTransfers::TICK);
+        transfers.expire();
+        assert_eq!(transfers.table.len(), 1);
+        assert!(transfers.table.get(&[1; 32]).is_none());
+        clock.advance(2 * RldpTransfers::TICK);
+        transfers.expire();
+        assert_eq!(transfers.expired.load(Ordering::Relaxed), 2);
+        clock.advance(RldpTransfers::LINGER + RldpTransfers::TICK);
+        transfers.expire();
+        assert_eq!(transfers.table.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stuck_transfer_closes_queue() {
+        let clock = TestClock::new();
+        let transfers = test_transfers(&clock);
+        let (queue, mut reader) = transfers.queue();
+        transfers.insert(&TRANSFER_ID, &queue);
+        drop(queue);
+        clock.advance(RldpTransfers::STUCK + RldpTransfers::TICK);
+        transfers.expire();
+        // Owning task is stopped, late packets find done transfer
+        assert!(reader.recv().await.is_none());
+        assert!(matches!(
+            transfers.table.get(&TRANSFER_ID).as_deref(),
+            Some(RldpTransfer::Done(_))
+        ));
+    }
+
+    #[test]
+    fn test_touched_transfer_rescheduled() {
+        let clock = TestClock::new();
+        let transfers = test_transfers(&clock);
+        let (queue, _reader) = transfers.queue();
+        transfers.insert(&TRANSFER_ID, &queue);
+        clock.advance(RldpTransfers::STUCK / 2);
+        let packet = RldpComplete {
+            transfer_id: ton::int256(TRANSFER_ID),
+            part: 0,
+        }
+        .into_boxed();
+        transfers.push(&queue, packet);
+        // Entry is due, but transfer got packet since scheduled
+        clock.advance(RldpTransfers::STUCK / 2 + RldpTransfers::TICK);
+        transfers.expire();
+        assert_eq!(transfers.table.len(), 1);
+        assert_eq!(transfers.expired.load(Ordering::Relaxed), 0);
+        clock.advance(RldpTransfers::STUCK / 2);
+        transfers.expire();
+        assert!(matches!(
+            transfers.table.get(&TRANSFER_ID).as_deref(),
+            Some(RldpTransfer::Done(_))
+        ));
+        assert_eq!(transfers.expired.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_packets() {
+        let transfers = RldpTransfers::with_options(&RldpNodeOptions {
+            transfer_queue_size: 1,
+            ..Default::default()
+        });
+        let (queue, mut reader) = transfers.queue();
+        for part in 0..3 {
+            let packet = RldpComplete {
+                transfer_id: ton::int256(TRANSFER_ID),
+                part,
+            }
+            .into_boxed();
+            transfers.push(&queue, packet)
+        }
+        let mut stats = RldpNodeStats::default();
+        transfers.fill_stats(&mut stats);
+        assert_eq!(stats.packets_dropped, 2);
+        // Oldest packet is kept
+        match reader.recv().await.unwrap() {
+            RldpMessagePartBoxed::Rldp_Complete(complete) => assert_eq!(complete.part, 0),
+            _ => panic!("unexpected packet"),
+        }
+        assert!(reader.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_done_transfer_lingers() {
+        let clock = TestClock::new();
+        let transfers = test_transfers(&clock);
+        let (queue, _reader) = transfers.queue();
+        transfers.insert(&TRANSFER_ID, &queue);
+        transfers.done(&TRANSFER_ID);
+        clock.advance(RldpTransfers::LINGER - RldpTransfers::TICK);
+        transfers.expire();
+        assert!(matches!(
+            transfers.table.get(&TRANSFER_ID).as_deref(),
+            Some(RldpTransfer::Done(_))
+        ));
+        clock.advance(2 * RldpTransfers::TICK);
+        transfers.expire();
+        assert_eq!(transfers.table.len(), 0);
+        // Done transfer is not counted as stuck one
+        assert_eq!(transfers.expired.load(Ordering::Relaxed), 0);
+        // Entry of insertion is due later, it finds nothing
+        clock.advance(RldpTransfers::STUCK);
+        transfers.expire();
+        assert_eq!(transfers.expired.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use adnl::common::*;
+use ton_api::ton::rldp::message::Answer as RldpAnswer;
+use ton_api::ton::rldp::message::Query as RldpQuery;
+use ton_api::ton::rldp::messagepart::MessagePart as RldpMessagePart;
+use ton_api::ton::rldp::MessagePart as RldpMessagePartBoxed;
+use ton_api::{ton, IntoBoxed};
+use ton_types::Result;
+
+use crate::{
+    RaptorqEncoder, RldpClock, RldpNode, RldpSendSource, RldpSendTransfer, RldpSourceSubscriber,
+    RldpTransport, TransferId,
+};
+
+pub(crate) const TRANSFER_ID: TransferId = [7u8; 32];
+
+pub(crate) fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 + i / 251) as u8).collect()
+}
+
+// Drives sender one tick: encodes requested parts and takes due packets
+pub(crate) fn send_tick(send: &mut RldpSendTransfer, data: &[u8], now: Instant) -> Vec<Vec<u8>> {
+    while let Some(request) = send.poll_part() {
+        let part = &data[request.offset..request.offset + request.len];
+        send.provide_part(request.part, RaptorqEncoder::with_data(part), now)
+    }
+    send.handle_timer(now);
+    let mut packets = Vec::new();
+    let mut buf = Vec::new();
+    while send.poll_outgoing(&mut buf).unwrap() {
+        packets.push(buf.clone())
+    }
+    packets
+}
+
+pub(crate) fn parse_packet(packet: &[u8]) -> RldpMessagePartBoxed {
+    deserialize(packet)
+        .unwrap()
+        .downcast::<RldpMessagePartBoxed>()
+        .unwrap()
+}
+
+pub(crate) fn message_part(packet: &[u8]) -> RldpMessagePart {
+    match parse_packet(packet) {
+        RldpMessagePartBoxed::Rldp_MessagePart(msg) => *msg,
+        _ => panic!("Message part expected"),
+    }
+}
+
+// Source symbols of part followed by some repair symbols
+pub(crate) fn part_packets(data: &[u8], part: i32, total: usize) -> Vec<RldpMessagePart> {
+    let mut encoder = RaptorqEncoder::with_data(data);
+    let count = encoder.params().symbols_count as u32 + 10;
+    (0..count)
+        .map(|mut seqno| {
+            let symbol = encoder.encode(&mut seqno).unwrap();
+            RldpMessagePart {
+                transfer_id: ton::int256(TRANSFER_ID),
+                fec_type: encoder.params().clone().into_boxed(),
+                part,
+                total_size: total as i64,
+                seqno: seqno as i32,
+                data: ton::bytes(symbol),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub(crate) struct TestClock(std::sync::Mutex<Instant>);
+
+impl TestClock {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Self(std::sync::Mutex::new(Instant::now())))
+    }
+
+    pub(crate) fn advance(&self, millis: u64) {
+        *self.0.lock().unwrap() += Duration::from_millis(millis)
+    }
+}
+
+impl RldpClock for TestClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TestTransport {
+    pub(crate) sent: std::sync::Mutex<Vec<Vec<u8>>>,
+}
+
+#[async_trait::async_trait]
+impl RldpTransport for TestTransport {
+    async fn send_custom(&self, data: &[u8], _peers: &AdnlPeers) -> Result<()> {
+        self.sent.lock().unwrap().push(data.to_vec());
+        Ok(())
+    }
+}
+
+// Packets of query transfer as sent by peer
+pub(crate) fn query_packets(query: RldpQuery, now: Instant) -> Vec<Vec<u8>> {
+    let data = serialize(&query.into_boxed()).unwrap();
+    let mut send = RldpSendTransfer::new(TRANSFER_ID, data.len(), None, now);
+    send_tick(&mut send, &data, now)
+}
+
+pub(crate) fn test_query(data: &[u8]) -> RldpQuery {
+    RldpQuery {
+        query_id: ton::int256([1; 32]),
+        max_answer_size: 1024 * 1024,
+        timeout: now() + 10,
+        data: ton::bytes(data.to_vec()),
+    }
+}
+
+// Lengths around TL bytes prefix forms, and unaligned ones
+pub(crate) const TL_BYTES_LENGTHS: [usize; 7] = [0, 1, 253, 254, 255, 1001, (1 << 24) - 1];
+
+pub(crate) fn serialized_answer(data: &[u8]) -> Vec<u8> {
+    let answer = RldpAnswer {
+        query_id: ton::int256([3; 32]),
+        data: ton::bytes(data.to_vec()),
+    };
+    serialize(&answer.into_boxed()).unwrap()
+}
+
+// Delivers packets straight to peer node
+#[derive(Default)]
+pub(crate) struct LoopbackTransport {
+    pub(crate) peer: std::sync::Mutex<std::sync::Weak<RldpNode>>,
+}
+
+#[async_trait::async_trait]
+impl RldpTransport for LoopbackTransport {
+    async fn send_custom(&self, data: &[u8], peers: &AdnlPeers) -> Result<()> {
+        let peer = self.peer.lock().unwrap().upgrade();
+        if let Some(peer) = peer {
+            let peers = AdnlPeers::with_keys(peers.other().clone(), peers.local().clone());
+            peer.try_consume_custom(data, &peers).await?;
+        }
+        Ok(())
+    }
+}
+
+// Answers any query with given data
+pub(crate) struct DataSubscriber(pub(crate) Vec<u8>);
+
+#[async_trait::async_trait]
+impl RldpSourceSubscriber for DataSubscriber {
+    async fn try_answer(
+        &self,
+        _query: &RldpQuery,
+        _peers: &AdnlPeers,
+    ) -> Result<Option<Box<dyn RldpSendSource>>> {
+        Ok(Some(Box::new(self.0.clone())))
+    }
+}
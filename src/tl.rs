@@ -0,0 +1,61 @@
+use ton_types::{fail, Result};
+
+// Serialized TL object may end with empty bytes field to be filled with data later,
+// so data is copied only once instead of being wrapped into object
+pub(crate) fn fill_tl_bytes(buf: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    truncate_tl_bytes(buf)?;
+    write_tl_bytes_len(buf, data.len());
+    buf.extend_from_slice(data);
+    buf.resize(buf.len().div_ceil(4) * 4, 0);
+    Ok(())
+}
+
+pub(crate) fn truncate_tl_bytes(buf: &mut Vec<u8>) -> Result<()> {
+    // Empty bytes are zero length and 3 bytes of padding
+    if !buf.ends_with(&[0, 0, 0, 0]) {
+        fail!("INTERNAL ERROR: no empty bytes at the end of TL object")
+    }
+    buf.truncate(buf.len() - 4);
+    Ok(())
+}
+
+pub(crate) fn write_tl_bytes_len(buf: &mut Vec<u8>, len: usize) {
+    if len < 254 {
+        buf.push(len as u8)
+    } else if len < (1 << 24) {
+        buf.push(254);
+        buf.extend_from_slice(&(len as u32).to_le_bytes()[..3])
+    } else {
+        buf.push(255);
+        buf.extend_from_slice(&(len as u64).to_le_bytes()[..7])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use adnl::common::deserialize;
+    use ton_api::ton::rldp::Message as RldpMessageBoxed;
+
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_tl_bytes_roundtrip() {
+        for len in TL_BYTES_LENGTHS {
+            let data = test_data(len);
+            let mut buf = serialized_answer(&[]);
+            fill_tl_bytes(&mut buf, &data).unwrap();
+            assert_eq!(buf, serialized_answer(&data), "length {}", len);
+            match deserialize(&buf).unwrap().downcast::<RldpMessageBoxed>() {
+                Ok(RldpMessageBoxed::Rldp_Answer(answer)) => assert!(answer.data.0 == data),
+                _ => panic!("Answer expected"),
+            }
+            // Header of answer source is the same prefix
+            let mut header = serialized_answer(&[]);
+            truncate_tl_bytes(&mut header).unwrap();
+            write_tl_bytes_len(&mut header, len);
+            assert!(buf.starts_with(&header));
+            assert_eq!(buf.len(), (header.len() + len).div_ceil(4) * 4);
+        }
+    }
+}
@@ -0,0 +1,1031 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use adnl::common::serialize_inplace;
+use ton_api::ton::fec::{type_::RaptorQ as FecTypeRaptorQ, Type as FecType};
+use ton_api::ton::rldp::messagepart::Complete as RldpComplete;
+use ton_api::ton::rldp::messagepart::Confirm as RldpConfirm;
+use ton_api::ton::rldp::messagepart::MessagePart as RldpMessagePart;
+use ton_api::ton::rldp::MessagePart as RldpMessagePartBoxed;
+use ton_api::{ton, IntoBoxed};
+use ton_types::{fail, Result};
+
+use crate::codec::{RaptorqDecodeJob, RaptorqDecoder, RaptorqEncoder};
+use crate::tl::fill_tl_bytes;
+use crate::{TransferId, TARGET};
+
+/// Status of RLDP transfer state machine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RldpTransferStatus {
+    /// Transfer is in progress
+    Active,
+    /// All data is sent or received
+    Done,
+    /// Peer stopped responding
+    TimedOut,
+    /// Peer broke limits of transfer
+    Failed,
+}
+
+// Roundtrip estimation and inactivity timeout of transfer
+pub(crate) struct RldpTimer {
+    roundtrip: u64,
+    start: Instant,
+    timeout: u64,
+}
+
+impl RldpTimer {
+    pub(crate) const SPINNER: u64 = 10; // Milliseconds
+    pub(crate) const TIMEOUT_MAX: u64 = 10000; // Milliseconds
+    pub(crate) const TIMEOUT_MIN: u64 = 500; // Milliseconds
+
+    fn new(roundtrip: Option<u64>, now: Instant) -> Self {
+        Self {
+            roundtrip: roundtrip.unwrap_or(0),
+            start: now,
+            timeout: Self::calc_timeout(roundtrip),
+        }
+    }
+
+    pub(crate) fn calc_timeout(roundtrip: Option<u64>) -> u64 {
+        std::cmp::max(
+            roundtrip.unwrap_or(RldpTimer::TIMEOUT_MAX),
+            RldpTimer::TIMEOUT_MIN,
+        )
+    }
+
+    fn elapsed(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_millis() as u64
+    }
+
+    fn is_timed_out(&self, updates: u32, now: Instant) -> bool {
+        self.elapsed(now) > self.timeout + self.timeout * updates as u64 / 100
+    }
+
+    fn update(&mut self, now: Instant) {
+        self.roundtrip = if self.roundtrip == 0 {
+            self.elapsed(now)
+        } else {
+            (self.roundtrip + self.elapsed(now)) / 2
+        };
+        self.timeout = Self::calc_timeout(Some(self.roundtrip));
+        self.start = now;
+    }
+}
+
+enum RecvReply {
+    Complete,
+    Confirm,
+}
+
+/// Receiving side of RLDP transfer, IO-free: packets and time are fed by driver,
+/// replies and received data are polled back
+pub struct RldpRecvTransfer {
+    complete: RldpMessagePartBoxed,
+    confirm: RldpMessagePartBoxed,
+    confirm_count: usize,
+    data: Vec<u8>,
+    deadline: Option<Instant>,
+    decoded: BTreeMap<u32, Vec<u8>>,
+    decoders: BTreeMap<u32, RaptorqDecoder>,
+    max_size: Option<usize>,
+    part: u32,
+    ready: VecDeque<Vec<u8>>,
+    received: usize,
+    reply: Option<RecvReply>,
+    status: RldpTransferStatus,
+    streaming: bool,
+    tick: Option<Instant>,
+    timer: Option<RldpTimer>,
+    total_size: Option<usize>,
+    transfer_id: TransferId,
+    updates: u32,
+    updates_seen: u32,
+}
+
+impl RldpRecvTransfer {
+    const LOOKAHEAD: u32 = 2; // Parts to be buffered ahead of current one
+    const MAX_PART_SIZE: usize = RldpSendTransfer::SLICE; // Both sides slice data alike
+    const MAX_SEQNO: u32 = (1 << 24) - 1; // Limit of RaptorQ encoding symbol ID
+    const MAX_SYMBOL_SIZE: i32 = 4096;
+
+    /// Constructor, streaming transfer keeps received parts for polling instead of assembling
+    pub fn new(transfer_id: TransferId, streaming: bool) -> Self {
+        Self {
+            complete: RldpComplete {
+                transfer_id: ton::int256(transfer_id),
+                part: 0,
+            }
+            .into_boxed(),
+            confirm: RldpConfirm {
+                transfer_id: ton::int256(transfer_id),
+                part: 0,
+                seqno: 0,
+            }
+            .into_boxed(),
+            confirm_count: 0,
+            data: Vec::new(),
+            deadline: None,
+            decoded: BTreeMap::new(),
+            decoders: BTreeMap::new(),
+            max_size: None,
+            part: 0,
+            ready: VecDeque::new(),
+            received: 0,
+            reply: None,
+            status: RldpTransferStatus::Active,
+            streaming,
+            tick: None,
+            timer: None,
+            total_size: None,
+            transfer_id,
+            updates: 0,
+            updates_seen: 0,
+        }
+    }
+
+    /// Transfer ID
+    pub fn transfer_id(&self) -> &TransferId {
+        &self.transfer_id
+    }
+
+    /// Status
+    pub fn status(&self) -> RldpTransferStatus {
+        self.status
+    }
+
+    /// Bytes received so far
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Total bytes to receive, known after first packet
+    pub fn total_size(&self) -> Option<usize> {
+        self.total_size
+    }
+
+    /// Roundtrip estimated while waiting, milliseconds
+    pub fn roundtrip(&self) -> Option<u64> {
+        self.timer.as_ref().map(|timer| timer.roundtrip)
+    }
+
+    /// Give up receiving at deadline regardless of activity
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline)
+    }
+
+    /// Fail transfer which is announced to be bigger than limit
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = Some(max_size)
+    }
+
+    /// Give up receiving on inactivity, timeout is estimated from roundtrip
+    pub fn start_waiting(&mut self, roundtrip: u64, now: Instant) {
+        self.timer = Some(RldpTimer::new(Some(roundtrip), now));
+        self.tick = Some(now + Duration::from_millis(RldpTimer::SPINNER));
+        self.updates_seen = self.updates;
+    }
+
+    /// Next time to call handle_timer
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.status != RldpTransferStatus::Active {
+            return None;
+        }
+        match (self.tick, self.deadline) {
+            (Some(tick), Some(deadline)) => Some(std::cmp::min(tick, deadline)),
+            (tick, deadline) => tick.or(deadline),
+        }
+    }
+
+    /// Handle timer
+    pub fn handle_timer(&mut self, now: Instant) {
+        if self.status != RldpTransferStatus::Active {
+            return;
+        }
+        if matches!(self.deadline, Some(deadline) if now >= deadline) {
+            self.status = RldpTransferStatus::TimedOut;
+            return;
+        }
+        let timer = match (&mut self.timer, self.tick) {
+            (Some(timer), Some(tick)) if now >= tick => timer,
+            _ => return,
+        };
+        if self.updates > self.updates_seen {
+            timer.update(now);
+            self.updates_seen = self.updates;
+        } else if timer.is_timed_out(self.updates_seen, now) {
+            self.status = RldpTransferStatus::TimedOut;
+            return;
+        }
+        self.tick = Some(now + Duration::from_millis(RldpTimer::SPINNER));
+    }
+
+    /// Handle incoming message part, heavy decoding step is returned as a job
+    pub fn handle_packet(
+        &mut self,
+        message: RldpMessagePart,
+        now: Instant,
+    ) -> Result<Option<RaptorqDecodeJob>> {
+        self.updates += 1;
+        if message.transfer_id.0 != self.transfer_id {
+            fail!("Unexpected transfer ID in RLDP packet")
+        }
+        if self.status != RldpTransferStatus::Active {
+            return Ok(None);
+        }
+        let fec_type = if let FecType::Fec_RaptorQ(fec_type) = message.fec_type {
+            fec_type
+        } else {
+            fail!("Unsupported FEC type in RLDP packet")
+        };
+        if message.total_size < 0 {
+            fail!("Incorrect total size in RLDP packet")
+        }
+        let total_size = if let Some(total_size) = self.total_size {
+            if total_size != message.total_size as usize {
+                fail!("Incorrect total size in RLDP packet")
+            }
+            total_size
+        } else {
+            let total_size = message.total_size as usize;
+            if matches!(self.max_size, Some(max_size) if total_size > max_size) {
+                // Peer is not going to send less, so there is nothing to wait for
+                self.status = RldpTransferStatus::Failed;
+                fail!("Too big size {} for RLDP transfer", total_size)
+            }
+            self.total_size = Some(total_size);
+            if !self.streaming {
+                // Size is not trusted until data arrives
+                self.data
+                    .reserve_exact(std::cmp::min(total_size, Self::MAX_PART_SIZE));
+            }
+            total_size
+        };
+        let part = message.part as u32;
+        if (part < self.part) || self.decoded.contains_key(&part) {
+            self.complete()?.part = message.part;
+            self.reply = Some(RecvReply::Complete);
+            return Ok(None);
+        }
+        if part > self.part + Self::LOOKAHEAD {
+            return Ok(None);
+        }
+        if message.seqno as u32 > Self::MAX_SEQNO {
+            fail!("Incorrect seqno in RLDP packet")
+        }
+        if message.data.len() != fec_type.symbol_size as usize {
+            fail!("Incorrect symbol size in RLDP packet")
+        }
+        let mut decoder = if let Some(decoder) = self.decoders.remove(&part) {
+            if fec_type.as_ref() != &decoder.params {
+                self.decoders.insert(part, decoder);
+                fail!("Incorrect parameters in RLDP packet")
+            }
+            decoder
+        } else {
+            self.check_params(&fec_type, total_size)?;
+            RaptorqDecoder::with_params(*fec_type)
+        };
+        let (seqno, data) = (message.seqno as u32, message.data);
+        if decoder.is_decoding_step() {
+            return Ok(Some(RaptorqDecodeJob {
+                data: data.0,
+                decoded: None,
+                decoder,
+                part: message.part,
+                seqno,
+            }));
+        }
+        let decoded = decoder.decode_owned(seqno, data.0);
+        self.process_decoded(message.part, decoder, decoded, now)?;
+        Ok(None)
+    }
+
+    /// Complete decoding step returned from handle_packet
+    pub fn complete_job(&mut self, job: RaptorqDecodeJob, now: Instant) -> Result<()> {
+        self.process_decoded(job.part, job.decoder, job.decoded, now)
+    }
+
+    /// Take reply to be sent to peer
+    pub fn poll_outgoing(&mut self, buf: &mut Vec<u8>) -> Result<bool> {
+        match self.reply.take() {
+            Some(RecvReply::Complete) => serialize_inplace(buf, &self.complete)?,
+            Some(RecvReply::Confirm) => serialize_inplace(buf, &self.confirm)?,
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Take received part of data, streaming transfer only
+    pub fn poll_data(&mut self) -> Option<Vec<u8>> {
+        let data = self.ready.pop_front()?;
+        self.updates += 1;
+        Some(data)
+    }
+
+    /// Take received data, whole transfer unless streaming
+    pub fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
+    fn assemble(&mut self, total_size: usize) -> Result<()> {
+        while let Some(mut data) = self.decoded.remove(&self.part) {
+            if data.len() + self.received > total_size {
+                fail!("Too big size for RLDP transfer")
+            }
+            self.received += data.len();
+            if self.streaming {
+                self.ready.push_back(data)
+            } else {
+                self.data.append(&mut data)
+            }
+            self.part += 1;
+            self.confirm_count = 0;
+        }
+        Ok(())
+    }
+
+    // Decoder memory depends on parameters, so they are checked before decoder creation
+    fn check_params(&self, params: &FecTypeRaptorQ, total_size: usize) -> Result<()> {
+        let (data_size, symbol_size) = (params.data_size, params.symbol_size);
+        if (data_size <= 0)
+            || (data_size as usize > std::cmp::min(total_size - self.received, Self::MAX_PART_SIZE))
+        {
+            fail!("Incorrect data size in RLDP packet")
+        }
+        // RaptorQ aligns symbols to 8 bytes
+        if !(8..=Self::MAX_SYMBOL_SIZE).contains(&symbol_size) || (symbol_size % 8 != 0) {
+            fail!("Incorrect symbol size in RLDP packet")
+        }
+        if params.symbols_count != (data_size + symbol_size - 1) / symbol_size {
+            fail!("Incorrect symbols count in RLDP packet")
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Result<&mut RldpComplete> {
+        match self.complete {
+            RldpMessagePartBoxed::Rldp_Complete(ref mut msg) => Ok(msg),
+            _ => fail!("INTERNAL ERROR: RLDP complete message mismatch"),
+        }
+    }
+
+    fn confirm(&mut self) -> Result<&mut RldpConfirm> {
+        match self.confirm {
+            RldpMessagePartBoxed::Rldp_Confirm(ref mut msg) => Ok(msg),
+            _ => fail!("INTERNAL ERROR: RLDP confirm message mismatch"),
+        }
+    }
+
+    fn process_decoded(
+        &mut self,
+        part: i32,
+        decoder: RaptorqDecoder,
+        decoded: Option<Vec<u8>>,
+        now: Instant,
+    ) -> Result<()> {
+        let total_size = if let Some(total_size) = self.total_size {
+            total_size
+        } else {
+            fail!("INTERNAL ERROR: RLDP total size mismatch")
+        };
+        if let Some(data) = decoded {
+            self.decoded.insert(part as u32, data);
+            self.assemble(total_size)?;
+            self.complete()?.part = part;
+            self.reply = Some(RecvReply::Complete);
+            if self.received == total_size {
+                if let Some(timer) = &mut self.timer {
+                    timer.update(now)
+                }
+                self.status = RldpTransferStatus::Done
+            }
+        } else if self.confirm_count == 9 {
+            let max_seqno = decoder.seqno;
+            self.decoders.insert(part as u32, decoder);
+            let confirm = self.confirm()?;
+            confirm.part = part;
+            confirm.seqno = max_seqno as i32;
+            self.confirm_count = 0;
+            self.reply = Some(RecvReply::Confirm);
+        } else {
+            self.decoders.insert(part as u32, decoder);
+            self.confirm_count += 1;
+        }
+        Ok(())
+    }
+}
+
+struct SendPart {
+    encoder: RaptorqEncoder,
+    part: u32,
+    seqno: u32,
+}
+
+/// Part of data to be read, encoded and provided to sending side of RLDP transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RldpPartRequest {
+    /// Part number
+    pub part: u32,
+    /// Offset of part in data
+    pub offset: usize,
+    /// Length of part
+    pub len: usize,
+}
+
+/// Sending side of RLDP transfer, IO-free: encoded parts, packets and time are fed by driver,
+/// packets to be sent are polled back
+pub struct RldpSendTransfer {
+    budget: u32,
+    lookahead: bool,
+    lookahead_turn: bool,
+    message: RldpMessagePartBoxed,
+    part: u32,
+    parts: VecDeque<SendPart>,
+    recv_seqno: u32,
+    reply: bool,
+    seqno_recv: u32,
+    seqno_sent: u32,
+    started: bool,
+    status: RldpTransferStatus,
+    tick: Instant,
+    timer: RldpTimer,
+    total: usize,
+    transfer_id: TransferId,
+    wave: u32,
+}
+
+impl RldpSendTransfer {
+    const LOOKAHEAD: usize = 1; // Parts to be encoded ahead of current one
+    const SLICE: usize = 2000000;
+    pub(crate) const SYMBOL: usize = 768;
+    const WAVE: u32 = 10; // Symbols per tick
+    const WINDOW: usize = 1000;
+
+    /// Constructor, known roundtrip speeds up timeout detection
+    pub fn new(
+        transfer_id: TransferId,
+        total: usize,
+        roundtrip: Option<u64>,
+        now: Instant,
+    ) -> Self {
+        let message = RldpMessagePart {
+            transfer_id: ton::int256(transfer_id),
+            fec_type: FecTypeRaptorQ {
+                data_size: 0,
+                symbol_size: Self::SYMBOL as i32,
+                symbols_count: 0,
+            }
+            .into_boxed(),
+            part: 0,
+            total_size: 0,
+            seqno: 0,
+            data: ton::bytes(Vec::new()),
+        }
+        .into_boxed();
+        Self {
+            budget: 0,
+            lookahead: false,
+            lookahead_turn: false,
+            message,
+            part: 0,
+            parts: VecDeque::new(),
+            recv_seqno: 0,
+            reply: false,
+            seqno_recv: 0,
+            seqno_sent: 0,
+            started: false,
+            status: if total == 0 {
+                RldpTransferStatus::Done
+            } else {
+                RldpTransferStatus::Active
+            },
+            tick: now,
+            timer: RldpTimer::new(roundtrip, now),
+            total,
+            transfer_id,
+            wave: 0,
+        }
+    }
+
+    /// Transfer ID
+    pub fn transfer_id(&self) -> &TransferId {
+        &self.transfer_id
+    }
+
+    /// Status
+    pub fn status(&self) -> RldpTransferStatus {
+        self.status
+    }
+
+    /// Roundtrip estimation, milliseconds
+    pub fn roundtrip(&self) -> u64 {
+        self.timer.roundtrip
+    }
+
+    /// Send symbols of next part ahead of current one, receiver must keep them
+    pub fn set_lookahead(&mut self, lookahead: bool) {
+        self.lookahead = lookahead
+    }
+
+    /// Part to be provided before sending may go on
+    pub fn poll_part(&self) -> Option<RldpPartRequest> {
+        if self.status != RldpTransferStatus::Active {
+            return None;
+        }
+        let part = match (self.parts.front(), self.parts.back()) {
+            (Some(current), Some(last)) => {
+                // Next part is encoded when source symbols of current one are sent
+                if current.encoder.has_source_packets() || (self.parts.len() > Self::LOOKAHEAD) {
+                    return None;
+                }
+                last.part + 1
+            }
+            _ => self.part,
+        };
+        let offset = part as usize * Self::SLICE;
+        if offset >= self.total {
+            return None;
+        }
+        Some(RldpPartRequest {
+            part,
+            offset,
+            len: std::cmp::min(self.total - offset, Self::SLICE),
+        })
+    }
+
+    /// Provide part requested by poll_part
+    pub fn provide_part(&mut self, part: u32, encoder: RaptorqEncoder, now: Instant) {
+        let expected = self
+            .parts
+            .back()
+            .map(|last| last.part + 1)
+            .unwrap_or(self.part);
+        if (self.status != RldpTransferStatus::Active) || (part != expected) {
+            return;
+        }
+        self.parts.push_back(SendPart {
+            encoder,
+            part,
+            seqno: 0,
+        });
+        if !self.started {
+            self.start_part(now)
+        }
+    }
+
+    /// Handle reply to the transfer, it means whole transfer is delivered
+    pub fn handle_reply(&mut self, now: Instant) {
+        self.reply = true;
+        if self.is_finished() {
+            self.finish(now)
+        }
+    }
+
+    /// Handle incoming confirm or complete message
+    pub fn handle_packet(&mut self, message: &RldpMessagePartBoxed, now: Instant) {
+        if (message.transfer_id().0 != self.transfer_id)
+            || (self.status != RldpTransferStatus::Active)
+        {
+            return;
+        }
+        match message {
+            RldpMessagePartBoxed::Rldp_Complete(msg) => {
+                if msg.part as u32 == self.part {
+                    self.part += 1;
+                    self.next_part(now)
+                }
+            }
+            RldpMessagePartBoxed::Rldp_Confirm(msg) => {
+                let seqno = msg.seqno as u32;
+                if (msg.part as u32 == self.part)
+                    && (self.seqno_sent >= seqno)
+                    && (self.seqno_recv < seqno)
+                {
+                    self.seqno_recv = seqno
+                }
+            }
+            RldpMessagePartBoxed::Rldp_MessagePart(_) => (),
+        }
+    }
+
+    /// Next time to call handle_timer
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if (self.status == RldpTransferStatus::Active) && self.started {
+            Some(self.tick)
+        } else {
+            None
+        }
+    }
+
+    /// Handle timer: check progress and allow next wave of packets
+    pub fn handle_timer(&mut self, now: Instant) {
+        if (self.status != RldpTransferStatus::Active) || !self.started || (now < self.tick) {
+            return;
+        }
+        if self.seqno_recv > self.recv_seqno {
+            log::trace!(
+                target: TARGET,
+                "Send updates {} -> {} in transfer {}",
+                self.recv_seqno,
+                self.seqno_recv,
+                base64::encode(&self.transfer_id)
+            );
+            self.timer.update(now);
+            self.recv_seqno = self.seqno_recv;
+        } else if self.timer.is_timed_out(self.recv_seqno, now) {
+            self.timer.roundtrip = std::cmp::min(self.timer.roundtrip * 2, RldpTimer::TIMEOUT_MAX);
+            self.status = RldpTransferStatus::TimedOut;
+            return;
+        }
+        self.budget = self.wave;
+        self.tick = now + Duration::from_millis(RldpTimer::SPINNER);
+    }
+
+    /// Take next packet to be sent, if any is due
+    pub fn poll_outgoing(&mut self, buf: &mut Vec<u8>) -> Result<bool> {
+        if (self.status != RldpTransferStatus::Active) || !self.started || (self.budget == 0) {
+            return Ok(false);
+        }
+        self.prepare_chunk(buf)?;
+        self.budget -= 1;
+        Ok(true)
+    }
+
+    fn finish(&mut self, now: Instant) {
+        if self.started {
+            self.timer.update(now)
+        }
+        self.parts.clear();
+        self.started = false;
+        self.status = RldpTransferStatus::Done;
+    }
+
+    fn is_finished(&self) -> bool {
+        self.reply && ((self.part as usize + 1) * Self::SLICE >= self.total)
+    }
+
+    fn is_lookahead_ready(&self) -> bool {
+        // Next part goes only after all source symbols of current one,
+        // it is not confirmed by receiver so it is bounded by window
+        match (self.parts.front(), self.parts.get(1)) {
+            (Some(current), Some(next)) if self.lookahead => {
+                !current.encoder.has_source_packets() && (next.seqno < Self::WINDOW as u32)
+            }
+            _ => false,
+        }
+    }
+
+    fn message(&mut self) -> Result<&mut RldpMessagePart> {
+        match self.message {
+            RldpMessagePartBoxed::Rldp_MessagePart(ref mut msg) => Ok(msg),
+            _ => fail!("INTERNAL ERROR: RLDP message mismatch"),
+        }
+    }
+
+    fn next_part(&mut self, now: Instant) {
+        if self.started {
+            self.timer.update(now);
+            self.started = false;
+        }
+        if self.is_finished() || (self.part as usize * Self::SLICE >= self.total) {
+            self.finish(now);
+            return;
+        }
+        while let Some(send_part) = self.parts.front() {
+            if send_part.part >= self.part {
+                break;
+            }
+            self.parts.pop_front();
+        }
+        self.start_part(now)
+    }
+
+    fn prepare_chunk(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        let lookahead = self.lookahead_turn && self.is_lookahead_ready();
+        self.lookahead_turn = !self.lookahead_turn;
+        let (send_part, seqno, symbol) = if lookahead {
+            let send_part = &mut self.parts[1];
+            let mut seqno = send_part.seqno;
+            let symbol = send_part.encoder.encode_symbol(&mut seqno)?;
+            send_part.seqno = seqno + 1;
+            (&self.parts[1], seqno, symbol)
+        } else if let Some(send_part) = self.parts.front_mut() {
+            let source = send_part.encoder.has_source_packets();
+            let mut seqno = self.seqno_sent;
+            let symbol = send_part.encoder.encode_symbol(&mut seqno)?;
+            // Source symbols are sent once even beyond window,
+            // so repair symbols must not reuse their IDs
+            if source || (seqno.saturating_sub(self.seqno_recv) <= Self::WINDOW as u32) {
+                self.seqno_sent = std::cmp::max(self.seqno_sent, seqno + 1);
+            }
+            (&self.parts[0], seqno, symbol)
+        } else {
+            fail!("Encoder is not ready");
+        };
+        let part = send_part.part as i32;
+        let data_size = send_part.encoder.params().data_size;
+        let symbols_count = send_part.encoder.params().symbols_count;
+        let total = self.total;
+        let message = self.message()?;
+        message.part = part;
+        message.total_size = total as i64;
+        message.seqno = seqno as i32;
+        match message.fec_type {
+            FecType::Fec_RaptorQ(ref mut fec_type) => {
+                fec_type.data_size = data_size;
+                fec_type.symbols_count = symbols_count;
+            }
+            _ => fail!("INTERNAL ERROR: unsupported FEC type"),
+        }
+        // Symbol is copied right into serialized message
+        serialize_inplace(buf, &self.message)?;
+        fill_tl_bytes(buf, symbol.data())
+    }
+
+    fn start_part(&mut self, now: Instant) {
+        let send_part = match self.parts.front() {
+            Some(send_part) if send_part.part == self.part => send_part,
+            _ => return,
+        };
+        // Lookahead part keeps its seqno, receiver has its symbols already
+        self.seqno_sent = send_part.seqno;
+        self.seqno_recv = 0;
+        self.recv_seqno = 0;
+        self.wave = std::cmp::min(send_part.encoder.params().symbols_count as u32, Self::WAVE);
+        self.budget = self.wave;
+        self.started = true;
+        self.tick = now + Duration::from_millis(RldpTimer::SPINNER);
+        self.timer.start = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn new_transfer(data: &[u8], now: Instant) -> (RldpSendTransfer, RldpRecvTransfer) {
+        let send = RldpSendTransfer::new(TRANSFER_ID, data.len(), None, now);
+        let recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        (send, recv)
+    }
+
+    fn deliver(recv: &mut RldpRecvTransfer, packet: &[u8], now: Instant) -> Result<()> {
+        if let Some(mut job) = recv.handle_packet(message_part(packet), now)? {
+            job.run();
+            recv.complete_job(job, now)?
+        }
+        Ok(())
+    }
+
+    fn reply(recv: &mut RldpRecvTransfer) -> Option<RldpMessagePartBoxed> {
+        let mut buf = Vec::new();
+        if recv.poll_outgoing(&mut buf).unwrap() {
+            Some(parse_packet(&buf))
+        } else {
+            None
+        }
+    }
+
+    // Runs transfer to the end, filter decides which packets reach receiver
+    fn run_transfer(
+        data: &[u8],
+        mut filter: impl FnMut(usize, &RldpMessagePart) -> bool,
+    ) -> (RldpSendTransfer, RldpRecvTransfer) {
+        let mut now = Instant::now();
+        let (mut send, mut recv) = new_transfer(data, now);
+        let mut count = 0;
+        while send.status() == RldpTransferStatus::Active {
+            for packet in send_tick(&mut send, data, now) {
+                count += 1;
+                if !filter(count, &message_part(&packet)) {
+                    continue;
+                }
+                deliver(&mut recv, &packet, now).unwrap();
+                if let Some(reply) = reply(&mut recv) {
+                    send.handle_packet(&reply, now)
+                }
+            }
+            now += Duration::from_millis(RldpTimer::SPINNER);
+        }
+        (send, recv)
+    }
+
+    #[test]
+    fn test_complete_confirm_handshake() {
+        let data = test_data(100_000);
+        let now = Instant::now();
+        let (mut send, mut recv) = new_transfer(&data, now);
+        let mut packets = Vec::new();
+        let mut tick = now;
+        while packets.len() < 10 {
+            packets.extend(send_tick(&mut send, &data, tick));
+            tick += Duration::from_millis(RldpTimer::SPINNER);
+        }
+        // Every 10th symbol is confirmed with the last seqno seen
+        for (i, packet) in packets.iter().take(10).enumerate() {
+            deliver(&mut recv, packet, tick).unwrap();
+            if i < 9 {
+                assert!(reply(&mut recv).is_none())
+            }
+        }
+        let confirm = reply(&mut recv).unwrap();
+        match &confirm {
+            RldpMessagePartBoxed::Rldp_Confirm(msg) => {
+                assert_eq!((msg.part, msg.seqno), (0, 9))
+            }
+            _ => panic!("Confirm expected"),
+        }
+        send.handle_packet(&confirm, tick);
+        assert_eq!(send.seqno_recv, 9);
+        // Decoded part is completed, sender finishes on complete
+        let mut seqno = 10;
+        while recv.status() == RldpTransferStatus::Active {
+            for packet in send_tick(&mut send, &data, tick) {
+                if message_part(&packet).seqno >= seqno {
+                    deliver(&mut recv, &packet, tick).unwrap();
+                    seqno += 1
+                }
+            }
+            tick += Duration::from_millis(RldpTimer::SPINNER);
+        }
+        assert_eq!(recv.take_data(), data);
+        let complete = loop {
+            match reply(&mut recv) {
+                Some(msg @ RldpMessagePartBoxed::Rldp_Complete(_)) => break msg,
+                Some(_) => (),
+                None => panic!("Complete expected"),
+            }
+        };
+        assert_eq!(send.status(), RldpTransferStatus::Active);
+        send.handle_packet(&complete, tick);
+        assert_eq!(send.status(), RldpTransferStatus::Done);
+        assert!(send.poll_timeout().is_none());
+        // Stale part is completed again
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        recv.part = 1;
+        deliver(&mut recv, &packets[0], tick).unwrap();
+        assert!(matches!(
+            reply(&mut recv),
+            Some(RldpMessagePartBoxed::Rldp_Complete(msg)) if msg.part == 0
+        ))
+    }
+
+    #[test]
+    fn test_loss_and_recovery() {
+        let data = test_data(100_000);
+        let (send, mut recv) = run_transfer(&data, |count, _| count % 3 != 0);
+        assert_eq!(send.status(), RldpTransferStatus::Done);
+        assert_eq!(recv.status(), RldpTransferStatus::Done);
+        assert_eq!(recv.take_data(), data);
+        // Burst loss at the start of transfer
+        let (send, mut recv) = run_transfer(&data, |count, _| count > 200);
+        assert_eq!(send.status(), RldpTransferStatus::Done);
+        assert_eq!(recv.take_data(), data);
+    }
+
+    #[test]
+    fn test_lookahead_before_current_part() {
+        let data = test_data(60_000);
+        let now = Instant::now();
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        let (first, second) = data.split_at(30_000);
+        // Next part arrives first and is buffered until current one is complete
+        for msg in part_packets(second, 1, data.len()) {
+            if let Some(mut job) = recv.handle_packet(msg, now).unwrap() {
+                job.run();
+                recv.complete_job(job, now).unwrap()
+            }
+        }
+        assert!(recv.decoded.contains_key(&1));
+        assert_eq!(recv.received(), 0);
+        // Current part releases buffered one
+        for msg in part_packets(first, 0, data.len()) {
+            if let Some(mut job) = recv.handle_packet(msg, now).unwrap() {
+                job.run();
+                recv.complete_job(job, now).unwrap()
+            }
+            if recv.status() == RldpTransferStatus::Done {
+                break;
+            }
+        }
+        assert_eq!(recv.status(), RldpTransferStatus::Done);
+        assert_eq!(recv.take_data(), data);
+        // Parts too far ahead are ignored
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        let part = RldpRecvTransfer::LOOKAHEAD as i32 + 1;
+        let msg = part_packets(second, part, data.len()).remove(0);
+        assert!(recv.handle_packet(msg, now).unwrap().is_none());
+        assert!(recv.decoders.is_empty())
+    }
+
+    #[test]
+    fn test_inactivity_timeout() {
+        let start = Instant::now();
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        recv.start_waiting(100, start);
+        let mut now = start;
+        while recv.status() == RldpTransferStatus::Active {
+            now = recv.poll_timeout().unwrap();
+            recv.handle_timer(now)
+        }
+        assert_eq!(recv.status(), RldpTransferStatus::TimedOut);
+        let elapsed = now.duration_since(start).as_millis() as u64;
+        assert!(elapsed > RldpTimer::TIMEOUT_MIN);
+        assert!(elapsed <= RldpTimer::TIMEOUT_MIN + RldpTimer::SPINNER);
+        assert!(recv.poll_timeout().is_none());
+        // Deadline wins regardless of activity
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        recv.set_deadline(start + Duration::from_millis(50));
+        recv.handle_timer(start + Duration::from_millis(49));
+        assert_eq!(recv.status(), RldpTransferStatus::Active);
+        recv.handle_timer(start + Duration::from_millis(50));
+        assert_eq!(recv.status(), RldpTransferStatus::TimedOut);
+        // Sender without confirms gives up, roundtrip is backed off
+        let data = test_data(100_000);
+        let mut send = RldpSendTransfer::new(TRANSFER_ID, data.len(), Some(100), start);
+        let mut now = start;
+        while send.status() == RldpTransferStatus::Active {
+            send_tick(&mut send, &data, now);
+            now = send.poll_timeout().unwrap_or(now);
+        }
+        assert_eq!(send.status(), RldpTransferStatus::TimedOut);
+        assert!(now.duration_since(start).as_millis() as u64 > RldpTimer::TIMEOUT_MIN);
+        assert_eq!(send.roundtrip(), 200);
+    }
+
+    #[test]
+    fn test_fec_params_rejected() {
+        let data = test_data(100_000);
+        let now = Instant::now();
+        let (mut send, _) = new_transfer(&data, now);
+        let packets = send_tick(&mut send, &data, now);
+        let set_params = |data_size, symbol_size, symbols_count| {
+            let mut msg = message_part(&packets[0]);
+            msg.fec_type = FecTypeRaptorQ {
+                data_size,
+                symbol_size,
+                symbols_count,
+            }
+            .into_boxed();
+            msg
+        };
+        for (data_size, symbol_size, symbols_count) in [
+            (0, 768, 0),
+            (200_000, 768, 261),
+            (100_000, 768, 100),
+            (100_000, 100, 1000),
+            (100_000, 8192, 13),
+        ] {
+            let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+            let msg = set_params(data_size, symbol_size, symbols_count);
+            assert!(recv.handle_packet(msg, now).is_err());
+            assert!(recv.decoders.is_empty())
+        }
+        // Part may not exceed slice even if transfer is big enough
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        let data_size = RldpSendTransfer::SLICE as i32 + 768;
+        let mut msg = set_params(data_size, 768, (data_size + 767) / 768);
+        msg.total_size = 3 * RldpSendTransfer::SLICE as i64;
+        assert!(recv.handle_packet(msg, now).is_err());
+        // Parameters of part may not change once decoding started
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        deliver(&mut recv, &packets[0], now).unwrap();
+        let msg = set_params(50_000, 768, 66);
+        assert!(recv.handle_packet(msg, now).is_err());
+        assert_eq!(recv.decoders[&0].params.data_size, 100_000);
+        deliver(&mut recv, &packets[1], now).unwrap();
+        // Total size may not change either
+        let mut msg = message_part(&packets[2]);
+        msg.total_size += 1;
+        assert!(recv.handle_packet(msg, now).is_err());
+    }
+
+    #[test]
+    fn test_total_size_limit() {
+        let data = test_data(10_000);
+        let now = Instant::now();
+        let mut packets = part_packets(&data, 0, data.len());
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        recv.set_max_size(data.len() - 1);
+        assert!(recv.handle_packet(packets[0].clone(), now).is_err());
+        assert_eq!(recv.status(), RldpTransferStatus::Failed);
+        assert!(recv.total_size().is_none());
+        assert!(recv.decoders.is_empty());
+        assert!(recv.poll_timeout().is_none());
+        // Limit itself may be reached
+        let mut recv = RldpRecvTransfer::new(TRANSFER_ID, false);
+        recv.set_max_size(data.len());
+        for msg in packets.drain(..) {
+            if let Some(mut job) = recv.handle_packet(msg, now).unwrap() {
+                job.run();
+                recv.complete_job(job, now).unwrap()
+            }
+            if recv.status() == RldpTransferStatus::Done {
+                break;
+            }
+        }
+        assert_eq!(recv.take_data(), data);
+    }
+}